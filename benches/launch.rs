@@ -0,0 +1,104 @@
+//! Measures launch latency of dbus-daemon depending on the file descriptor
+//! limit, together with the cost of each way of marking inherited file
+//! descriptors as close on exec.
+
+use dbus_launch::CloseOnExec;
+use std::io::Error;
+use std::mem::MaybeUninit;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+
+const STRATEGIES: [CloseOnExec; 3] = [
+    CloseOnExec::CloseRange,
+    CloseOnExec::ProcSelfFd,
+    CloseOnExec::Poll,
+];
+
+fn main() {
+    // Containers commonly raise the limit to 1M, try to do the same.
+    raise_hard_fd_limit(1 << 20);
+    let hard = get_fd_limit().1;
+    let mut limits = vec![1024, 65536, 1 << 20];
+    limits.retain(|&limit| limit <= hard);
+    if !limits.contains(&hard) {
+        limits.push(hard);
+    }
+
+    for limit in limits {
+        set_fd_limit(limit);
+        println!("RLIMIT_NOFILE={}", limit);
+        let elapsed = (0..ITERATIONS).map(|_| launch()).sum::<Duration>();
+        println!(
+            "  {:<12} {:>10.2?} per launch",
+            "launch",
+            elapsed / ITERATIONS
+        );
+        for &strategy in &STRATEGIES {
+            match close_on_exec(strategy) {
+                Some(elapsed) => println!(
+                    "  {:<12} {:>10.2?} per call",
+                    format!("{:?}", strategy),
+                    elapsed / ITERATIONS
+                ),
+                None => println!("  {:<12} {:>10}", format!("{:?}", strategy), "n/a"),
+            }
+        }
+    }
+}
+
+fn launch() -> Duration {
+    let start = Instant::now();
+    let daemon = dbus_launch::Launcher::daemon()
+        .launch()
+        .expect("failed to launch dbus-daemon");
+    let elapsed = start.elapsed();
+    drop(daemon);
+    elapsed
+}
+
+/// Returns the total time of marking all file descriptors of the benchmark
+/// close on exec with given strategy, or `None` if it is unsupported.
+fn close_on_exec(strategy: CloseOnExec) -> Option<Duration> {
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        match dbus_launch::close_on_exec_using(3, strategy) {
+            Ok(()) => elapsed += start.elapsed(),
+            Err(err) if err.kind() == std::io::ErrorKind::Unsupported => return None,
+            Err(err) => panic!("{:?}: {}", strategy, err),
+        }
+    }
+    Some(elapsed)
+}
+
+fn get_fd_limit() -> (libc::rlim_t, libc::rlim_t) {
+    let mut limit = MaybeUninit::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) } == -1 {
+        panic!("getrlimit: {}", Error::last_os_error());
+    }
+    let limit = unsafe { limit.assume_init() };
+    (limit.rlim_cur, limit.rlim_max)
+}
+
+/// Raises the hard limit if permitted, keeping the current one otherwise.
+fn raise_hard_fd_limit(hard: libc::rlim_t) {
+    let (soft, old) = get_fd_limit();
+    if hard > old {
+        let limit = libc::rlimit {
+            rlim_cur: soft,
+            rlim_max: hard,
+        };
+        unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) };
+    }
+}
+
+fn set_fd_limit(soft: libc::rlim_t) {
+    let limit = libc::rlimit {
+        rlim_cur: soft,
+        rlim_max: get_fd_limit().1,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == -1 {
+        panic!("setrlimit: {}", Error::last_os_error());
+    }
+}
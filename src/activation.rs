@@ -0,0 +1,250 @@
+//! Recording of service activations.
+//!
+//! Each service executable is wrapped in a shell script which appends to a
+//! log a start record before executing the service, and an exit record once
+//! it terminates. Records consist of NUL terminated fields:
+//!
+//! ```text
+//! start NAME KEY PID TIME ARGC ARGS...
+//! exit KEY STATUS
+//! ```
+//!
+//! The KEY is the PID of the wrapper script, which remains the parent of the
+//! service for its whole lifetime.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the directory with wrapper scripts and the log.
+pub(crate) const DIR: &str = "activation";
+
+/// Name of the log file within the directory.
+const LOG: &str = "log";
+
+/// A service activation, as recorded by the launcher.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Activation {
+    name: String,
+    pid: libc::pid_t,
+    args: Vec<OsString>,
+    started: SystemTime,
+    exit_code: Option<i32>,
+}
+
+impl Activation {
+    /// Returns the name of the activated service.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the PID of the service process.
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Returns arguments of the service process, starting with the
+    /// executable.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Returns the time the service was started.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Returns the exit code of the service, or `None` if it is still
+    /// running.
+    ///
+    /// Following the shell convention, termination by a signal is reported
+    /// as 128 plus the signal number.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+/// Script run by the wrapper in a child process with the log, service name
+/// and arguments. It records the start and then executes the service.
+const START: &[u8] = br#"name=$1; shift
+printf '%s\0' start "$name" "$PPID" "$$" "$(date +%s.%N)" "$#" "$@" >> "$0"
+exec "$@""#;
+
+/// Writes a script into `dir` that records activation of the service and
+/// executes it with given arguments. Returns the path of the script.
+pub(crate) fn write_wrapper(
+    dir: &Path,
+    name: &str,
+    argv: &[OsString],
+) -> io::Result<PathBuf> {
+    let log = dir.join(LOG);
+    let log = log.as_os_str().as_bytes();
+    let mut script = b"#!/bin/sh\nsh -c ".to_vec();
+    let argv = argv.iter().map(|arg| arg.as_bytes());
+    for arg in [START, log, name.as_bytes()].iter().copied().chain(argv) {
+        script.extend_from_slice(&quote(arg));
+        script.push(b' ');
+    }
+    script.extend_from_slice(
+        b"\"$@\"\nstatus=$?\nprintf '%s\\0' exit \"$$\" \"$status\" >> ",
+    );
+    script.extend_from_slice(&quote(log));
+    script.extend_from_slice(b"\nexit \"$status\"\n");
+
+    let path = dir.join(name);
+    fs::write(&path, script)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    Ok(path)
+}
+
+/// Returns true if the argument refers to a file within the directory, i.e.,
+/// the process is one of wrapper scripts rather than the service itself.
+pub(crate) fn is_wrapper_arg(dir: &Path, arg: &[u8]) -> bool {
+    Path::new(std::ffi::OsStr::from_bytes(arg)).starts_with(dir)
+}
+
+/// Reads activations recorded in the directory, in order they started.
+pub(crate) fn read_log(dir: &Path) -> io::Result<Vec<Activation>> {
+    match fs::read(dir.join(LOG)) {
+        Ok(log) => parse_log(&log),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_log(log: &[u8]) -> io::Result<Vec<Activation>> {
+    let mut activations: Vec<(libc::pid_t, Activation)> = Vec::new();
+    // The last record may be incomplete if it is being written concurrently.
+    let mut fields = log.split(|&b| b == 0);
+    fields.next_back();
+    while let Some(kind) = fields.next() {
+        match kind {
+            b"start" => {
+                let name = fields
+                    .next()
+                    .map(|f| String::from_utf8_lossy(f).into_owned());
+                let key = fields.next().and_then(parse);
+                let pid = fields.next().and_then(parse);
+                let started = fields.next().and_then(parse_time);
+                let argc = fields.next().and_then(parse::<usize>);
+                let (name, key, pid, started, argc) =
+                    match (name, key, pid, started, argc) {
+                        (Some(n), Some(k), Some(p), Some(s), Some(a)) => (n, k, p, s, a),
+                        _ => break,
+                    };
+                let args: Vec<_> = fields
+                    .by_ref()
+                    .take(argc)
+                    .map(|arg| OsString::from_vec(arg.to_vec()))
+                    .collect();
+                if args.len() != argc {
+                    break;
+                }
+                let activation = Activation {
+                    name,
+                    pid,
+                    args,
+                    started,
+                    exit_code: None,
+                };
+                activations.push((key, activation));
+            }
+            b"exit" => {
+                let key = fields.next().and_then(parse::<libc::pid_t>);
+                let status = fields.next().and_then(parse);
+                let (key, status) = match (key, status) {
+                    (Some(key), Some(status)) => (key, status),
+                    _ => break,
+                };
+                // PIDs may be reused, match the latest running one.
+                if let Some((_, activation)) = activations
+                    .iter_mut()
+                    .rev()
+                    .find(|(k, a)| *k == key && a.exit_code.is_none())
+                {
+                    activation.exit_code = Some(status);
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "malformed activation log",
+                ))
+            }
+        }
+    }
+    Ok(activations.into_iter().map(|(_, a)| a).collect())
+}
+
+fn parse<T: std::str::FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Parses time in seconds since epoch, with an optional fractional part,
+/// which is ignored if date lacks support for nanoseconds.
+fn parse_time(field: &[u8]) -> Option<SystemTime> {
+    let field = std::str::from_utf8(field).ok()?;
+    let (secs, frac) = field.split_once('.').unwrap_or((field, ""));
+    let mut time = UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?);
+    if !frac.is_empty() && frac.len() <= 9 && frac.bytes().all(|b| b.is_ascii_digit()) {
+        let nanos: u32 = frac.parse().ok()?;
+        time +=
+            Duration::from_nanos(u64::from(nanos) * 10u64.pow(9 - frac.len() as u32));
+    }
+    Some(time)
+}
+
+/// Quotes a string for use in a shell script.
+fn quote(s: &[u8]) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &b in s {
+        if b == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(b);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log() {
+        let log = b"start\0com.example.A\x0010\x0011\x001.5\x001\0/bin/a\0\
+                    start\0com.example.B\x0020\x0021\x002.N\x002\0/bin/b\0\0\
+                    exit\x0010\x003\0\
+                    start\0com.example.A\x0010\x0012\x003\x000\0\
+                    exit\x0020\0";
+        let activations = parse_log(log).unwrap();
+        assert_eq!(3, activations.len());
+
+        assert_eq!("com.example.A", activations[0].name());
+        assert_eq!(11, activations[0].pid());
+        assert_eq!(&["/bin/a"], activations[0].args());
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_millis(1500),
+            activations[0].started()
+        );
+        assert_eq!(Some(3), activations[0].exit_code());
+
+        assert_eq!("com.example.B", activations[1].name());
+        assert_eq!(&["/bin/b", ""], activations[1].args());
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(2),
+            activations[1].started()
+        );
+        assert_eq!(None, activations[1].exit_code());
+
+        assert_eq!(12, activations[2].pid());
+        assert!(activations[2].args().is_empty());
+        assert_eq!(None, activations[2].exit_code());
+    }
+}
@@ -0,0 +1,257 @@
+//! D-Bus server addresses.
+
+use crate::escape;
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::OwnedFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/// A parsed D-Bus address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Address {
+    pub(crate) transport: String,
+    /// Key-value pairs with unescaped values.
+    pub(crate) params: Vec<(String, Vec<u8>)>,
+}
+
+impl Address {
+    /// Parses a single address.
+    pub(crate) fn parse(s: &str) -> Result<Address> {
+        let (transport, rest) = match s.split_once(':') {
+            Some((transport, rest)) if !transport.is_empty() => (transport, rest),
+            _ => return Err(malformed(s, "missing transport")),
+        };
+        let mut params: Vec<(String, Vec<u8>)> = Vec::new();
+        for param in rest.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) if !key.is_empty() => (key, value),
+                _ => return Err(malformed(s, "expected key=value")),
+            };
+            if params.iter().any(|(k, _)| k == key) {
+                return Err(malformed(s, "duplicate key"));
+            }
+            let value = unescape(value).ok_or_else(|| malformed(s, "invalid escape"))?;
+            params.push((key.to_owned(), value));
+        }
+        Ok(Address {
+            transport: transport.to_owned(),
+            params,
+        })
+    }
+}
+
+fn malformed(address: &str, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("malformed address {:?}: {}", address, reason),
+    )
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = s.bytes();
+    let mut unescaped = Vec::new();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hi = (bytes.next()? as char).to_digit(16)?;
+            let lo = (bytes.next()? as char).to_digit(16)?;
+            unescaped.push((hi * 16 + lo) as u8);
+        } else {
+            unescaped.push(b);
+        }
+    }
+    Some(unescaped)
+}
+
+/// A Unix domain socket the daemon listens on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum UnixAddress {
+    Path(PathBuf),
+    Abstract(Vec<u8>),
+}
+
+impl UnixAddress {
+    /// Resolves a listen address into a concrete socket address, choosing
+    /// a random name where the address leaves it unspecified.
+    ///
+    /// Returns an error for transports other than unix.
+    pub(crate) fn from_listen(listen: &str) -> Result<UnixAddress> {
+        let address = Address::parse(listen)?;
+        if address.transport != "unix" {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported transport {:?}", address.transport),
+            ));
+        }
+        if address.params.len() != 1 {
+            return Err(malformed(
+                listen,
+                "expected exactly one of path, dir, tmpdir or abstract",
+            ));
+        }
+        let (key, value) = &address.params[0];
+        match key.as_str() {
+            "path" => Ok(UnixAddress::Path(to_path(value))),
+            "dir" | "tmpdir" => {
+                let name = format!("dbus-{}", random_name()?);
+                Ok(UnixAddress::Path(to_path(value).join(name)))
+            }
+            "abstract" if value.is_empty() => Ok(UnixAddress::Abstract(
+                format!("/tmp/dbus-{}", random_name()?).into(),
+            )),
+            "abstract" => Ok(UnixAddress::Abstract(value.clone())),
+            _ => Err(malformed(listen, "unsupported key")),
+        }
+    }
+
+    /// Returns the socket address of a unix address clients connect to,
+    /// which has either a path or an abstract name.
+    pub(crate) fn from_address(address: &Address) -> Result<UnixAddress> {
+        let param = |key: &str| {
+            address
+                .params
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_slice())
+        };
+        match (param("path"), param("abstract")) {
+            (Some(path), None) => Ok(UnixAddress::Path(to_path(path))),
+            (None, Some(name)) => Ok(UnixAddress::Abstract(name.to_vec())),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "expected unix address with path or abstract",
+            )),
+        }
+    }
+
+    /// Returns the address a listening socket is bound to.
+    pub(crate) fn from_socket(fd: &OwnedFd) -> Result<UnixAddress> {
+        let addr = UnixListener::from(fd.try_clone()?).local_addr()?;
+        if let Some(path) = addr.as_pathname() {
+            return Ok(UnixAddress::Path(path.to_owned()));
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            if let Some(name) = addr.as_abstract_name() {
+                return Ok(UnixAddress::Abstract(name.to_owned()));
+            }
+        }
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "listening socket is not bound to an address",
+        ))
+    }
+
+    /// Returns the address clients should connect to.
+    pub(crate) fn address(&self) -> String {
+        match self {
+            UnixAddress::Path(path) => format!("unix:path={}", crate::escape_path(path)),
+            UnixAddress::Abstract(name) => format!("unix:abstract={}", escape(name)),
+        }
+    }
+
+    pub(crate) fn bind(&self) -> Result<UnixListener> {
+        match self {
+            UnixAddress::Path(path) => UnixListener::bind(path),
+            UnixAddress::Abstract(name) => bind_abstract(name),
+        }
+    }
+
+    pub(crate) fn connect(&self) -> Result<UnixStream> {
+        match self {
+            UnixAddress::Path(path) => UnixStream::connect(path),
+            UnixAddress::Abstract(name) => connect_abstract(name),
+        }
+    }
+}
+
+fn to_path(value: &[u8]) -> PathBuf {
+    PathBuf::from(OsString::from_vec(value.to_vec()))
+}
+
+fn random_name() -> Result<String> {
+    let mut random = [0u8; 8];
+    crate::sys::random_bytes(&mut random)?;
+    Ok(random.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_abstract(name: &[u8]) -> Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    UnixListener::bind_addr(&addr)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn connect_abstract(name: &[u8]) -> Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    UnixStream::connect_addr(&addr)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_abstract(_name: &[u8]) -> Result<UnixListener> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "abstract sockets are not supported on this platform",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn connect_abstract(_name: &[u8]) -> Result<UnixStream> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "abstract sockets are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let a = Address::parse("unix:path=/tmp/a%2cb,guid=01").unwrap();
+        assert_eq!("unix", a.transport);
+        assert_eq!(
+            vec![
+                ("path".to_owned(), b"/tmp/a,b".to_vec()),
+                ("guid".to_owned(), b"01".to_vec())
+            ],
+            a.params
+        );
+
+        assert!(Address::parse("unix").is_err());
+        assert!(Address::parse(":path=/").is_err());
+        assert!(Address::parse("unix:path").is_err());
+        assert!(Address::parse("unix:path=%zz").is_err());
+        assert!(Address::parse("unix:path=/a,path=/b").is_err());
+    }
+
+    #[test]
+    fn listen() {
+        assert_eq!(
+            UnixAddress::Path("/tmp/socket".into()),
+            UnixAddress::from_listen("unix:path=/tmp/socket").unwrap()
+        );
+        match UnixAddress::from_listen("unix:dir=/tmp").unwrap() {
+            UnixAddress::Path(path) => assert!(path.starts_with("/tmp")),
+            a => panic!("unexpected {:?}", a),
+        }
+        assert_eq!(
+            "unix:abstract=a%20b",
+            UnixAddress::from_listen("unix:abstract=a%20b")
+                .unwrap()
+                .address()
+        );
+        assert_eq!(
+            ErrorKind::Unsupported,
+            UnixAddress::from_listen("tcp:host=localhost")
+                .unwrap_err()
+                .kind()
+        );
+        assert!(UnixAddress::from_listen("unix:runtime=yes").is_err());
+    }
+}
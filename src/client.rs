@@ -0,0 +1,653 @@
+//! A minimal synchronous D-Bus client.
+//!
+//! Intended for querying the launched message bus from tests, without
+//! depending on external tools or a full D-Bus library.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use dbus_launch::client::Value;
+//!
+//! let daemon = dbus_launch::Launcher::daemon().launch().unwrap();
+//! let mut conn = daemon.connect().unwrap();
+//! let reply = conn
+//!     .call(
+//!         "org.freedesktop.DBus",
+//!         "/org/freedesktop/DBus",
+//!         "org.freedesktop.DBus",
+//!         "NameHasOwner",
+//!         &[Value::String("com.example.Test".into())],
+//!     )
+//!     .unwrap();
+//! assert_eq!(vec![Value::Boolean(false)], reply);
+//! ```
+
+use crate::address::{Address, UnixAddress};
+use crate::message::{Message, MessageType};
+use crate::sys::{recv_with_fds, send_with_fds};
+use crate::{BusName, InterfaceName};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt::{self, Write as _};
+use std::io::{Error, ErrorKind, Result};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+pub use crate::message::Value;
+
+pub(crate) const BUS_NAME: &str = "org.freedesktop.DBus";
+pub(crate) const BUS_PATH: &str = "/org/freedesktop/DBus";
+
+/// A connection to a D-Bus peer over a Unix domain socket or TCP.
+#[derive(Debug)]
+pub struct Connection {
+    stream: Stream,
+    /// Unique name assigned by the message bus, empty before Hello.
+    unique_name: String,
+    /// Received bytes that were not parsed yet.
+    buf: Vec<u8>,
+    /// Received file descriptors that were not attached to a message yet.
+    fds: Vec<OwnedFd>,
+    /// Serial of the last message sent.
+    serial: u32,
+    /// Received messages that were not returned from recv yet.
+    queue: VecDeque<Message>,
+}
+
+#[derive(Debug)]
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    fn connect(address: &str) -> Result<Stream> {
+        let address = Address::parse(address)?;
+        let param = |key: &str| {
+            address
+                .params
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_slice())
+        };
+        match address.transport.as_str() {
+            "unix" => Ok(Stream::Unix(
+                UnixAddress::from_address(&address)?.connect()?,
+            )),
+            "tcp" => {
+                let host = param("host").unwrap_or(b"localhost");
+                let host = std::str::from_utf8(host)
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid host"))?;
+                let port = param("port")
+                    .and_then(|port| std::str::from_utf8(port).ok()?.parse::<u16>().ok())
+                    .ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "invalid port")
+                    })?;
+                let stream = TcpStream::connect((host, port))?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            transport => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported transport {:?}", transport),
+            )),
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Connection {
+    /// Connects to the message bus at given address, authenticates and sends
+    /// Hello.
+    ///
+    /// The address may contain multiple semicolon separated addresses, which
+    /// are tried in order. Supported are unix addresses with a path or an
+    /// abstract name, and tcp addresses. Authentication uses the EXTERNAL
+    /// mechanism, falling back to ANONYMOUS if the former is rejected.
+    ///
+    /// If connecting, authentication or Hello fails, the next address is
+    /// tried, and the error from the last one is returned.
+    pub fn open(address: &str) -> Result<Connection> {
+        let mut error = Error::new(ErrorKind::InvalidInput, "empty address");
+        for address in address.split(';').filter(|a| !a.is_empty()) {
+            match Connection::open_one(address) {
+                Ok(conn) => return Ok(conn),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn open_one(address: &str) -> Result<Connection> {
+        let mut conn = Connection::new(Stream::connect(address)?);
+        conn.authenticate()?;
+        conn.hello()?;
+        Ok(conn)
+    }
+
+    /// Authenticates using EXTERNAL mechanism on an already connected stream.
+    pub(crate) fn from_stream(stream: UnixStream) -> Result<Connection> {
+        let mut conn = Connection::new(Stream::Unix(stream));
+        conn.authenticate()?;
+        Ok(conn)
+    }
+
+    fn new(stream: Stream) -> Connection {
+        Connection {
+            stream,
+            unique_name: String::new(),
+            buf: Vec::new(),
+            fds: Vec::new(),
+            serial: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn authenticate(&mut self) -> Result<()> {
+        let mut uid = String::new();
+        for b in unsafe { libc::getuid() }.to_string().bytes() {
+            write!(&mut uid, "{:02x}", b).unwrap();
+        }
+
+        self.write_all(b"\0", &[])?;
+        self.write_all(format!("AUTH EXTERNAL {}\r\n", uid).as_bytes(), &[])?;
+        let mut line = self.read_line()?;
+        if let Some(mechanisms) = line.strip_prefix("REJECTED ") {
+            if mechanisms.split(' ').any(|m| m == "ANONYMOUS") {
+                // The trace is an arbitrary hex encoded string.
+                self.write_all(b"AUTH ANONYMOUS 646275732d6c61756e6368\r\n", &[])?;
+                line = self.read_line()?;
+            }
+        }
+        if !line.starts_with("OK ") {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("authentication failed: {}", line),
+            ));
+        }
+
+        if let Stream::Unix(_) = self.stream {
+            self.write_all(b"NEGOTIATE_UNIX_FD\r\n", &[])?;
+            let line = self.read_line()?;
+            if line != "AGREE_UNIX_FD" {
+                return Err(Error::other(format!(
+                    "unix fd passing not supported: {}",
+                    line
+                )));
+            }
+        }
+
+        self.write_all(b"BEGIN\r\n", &[])
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(n) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line: Vec<u8> = self.buf.drain(..n + 2).take(n).collect();
+                return String::from_utf8(line).map_err(|_| {
+                    Error::new(ErrorKind::InvalidData, "invalid SASL reply")
+                });
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Reads more data into the buffer.
+    fn fill(&mut self) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        let n = recv_with_fds(self.stream.as_raw_fd(), &mut buf, &mut self.fds)
+            .map_err(timed_out)?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed by peer",
+            ));
+        }
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(())
+    }
+
+    fn write_all(&mut self, mut buf: &[u8], mut fds: &[RawFd]) -> Result<()> {
+        while !buf.is_empty() {
+            let n =
+                send_with_fds(self.stream.as_raw_fd(), buf, fds).map_err(timed_out)?;
+            buf = &buf[n..];
+            fds = &[];
+        }
+        Ok(())
+    }
+
+    /// Sends a message and returns its serial.
+    pub(crate) fn send(&mut self, mut message: Message) -> Result<u32> {
+        self.serial = self.serial.checked_add(1).unwrap_or(1);
+        message.serial = self.serial;
+        let buf = message.marshal()?;
+        let fds: Vec<RawFd> = message.fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        self.write_all(&buf, &fds)?;
+        Ok(message.serial)
+    }
+
+    /// Receives the next message.
+    pub(crate) fn recv(&mut self) -> Result<Message> {
+        if let Some(message) = self.queue.pop_front() {
+            return Ok(message);
+        }
+        while self.buf.len() < 16 {
+            self.fill()?;
+        }
+        let len = Message::length(self.buf[..16].try_into().unwrap())?;
+        while self.buf.len() < len {
+            self.fill()?;
+        }
+        let message = Message::unmarshal(&self.buf[..len], &mut self.fds)?;
+        self.buf.drain(..len);
+        Ok(message)
+    }
+
+    /// Receives the next message in serialized form, discarding any file
+    /// descriptors attached to it. Messages already queued are not returned.
+    pub(crate) fn recv_raw(&mut self) -> Result<Vec<u8>> {
+        while self.buf.len() < 16 {
+            self.fill()?;
+        }
+        let len = Message::length(self.buf[..16].try_into().unwrap())?;
+        while self.buf.len() < len {
+            self.fill()?;
+        }
+        self.fds.clear();
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    /// Sends a method call and waits for a reply to it. Other messages
+    /// received in the meantime are queued.
+    pub(crate) fn call_message(&mut self, message: Message) -> Result<Message> {
+        let serial = self.send(message)?;
+        let mut skipped = VecDeque::new();
+        let reply = loop {
+            let message = self.recv()?;
+            match message.message_type {
+                MessageType::MethodReturn | MessageType::Error
+                    if message.reply_serial == Some(serial) =>
+                {
+                    break message;
+                }
+                _ => skipped.push_back(message),
+            }
+        };
+        skipped.append(&mut self.queue);
+        self.queue = skipped;
+        Ok(reply)
+    }
+
+    /// Returns the unique name assigned to the connection by the message bus.
+    pub fn unique_name(&self) -> &str {
+        &self.unique_name
+    }
+
+    /// Sets the timeout for sending and receiving, which applies to each
+    /// subsequent [`call`](Connection::call). `None` waits indefinitely,
+    /// which is the default.
+    ///
+    /// An operation that times out fails with [`ErrorKind::TimedOut`].
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        match self.stream {
+            Stream::Unix(ref stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Stream::Tcp(ref stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    /// Calls a method and waits for the reply. Returns the reply arguments,
+    /// or an error if the call failed. The error reply is available as
+    /// [`MethodError`] through [`MethodError::from_io`].
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the destination is not a valid
+    /// [`BusName`] or the interface is not a valid [`InterfaceName`].
+    pub fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        args: &[Value],
+    ) -> Result<Vec<Value>> {
+        BusName::new(destination)?;
+        InterfaceName::new(interface)?;
+        let mut call = Message::method_call(Some(destination), path, interface, member);
+        call.body.extend_from_slice(args);
+        self.method_call(call)
+    }
+
+    /// Sends Hello to the message bus and returns the assigned unique name.
+    pub(crate) fn hello(&mut self) -> Result<String> {
+        match self.bus_call("Hello", &[])?.first().and_then(Value::as_str) {
+            Some(name) => {
+                self.unique_name = name.to_owned();
+                Ok(self.unique_name.clone())
+            }
+            None => Err(Error::new(ErrorKind::InvalidData, "invalid reply to Hello")),
+        }
+    }
+
+    /// Returns the PID of the message bus itself.
+    pub(crate) fn bus_pid(&mut self) -> Result<u32> {
+        let name = Value::String(BUS_NAME.to_owned());
+        match self
+            .bus_call("GetConnectionUnixProcessID", &[name])?
+            .first()
+        {
+            Some(&Value::Uint32(pid)) => Ok(pid),
+            _ => Err(invalid_reply("GetConnectionUnixProcessID")),
+        }
+    }
+
+    /// Returns names currently owned on the bus, both unique and well-known.
+    pub fn list_names(&mut self) -> Result<Vec<BusName>> {
+        names(self.bus_call("ListNames", &[])?, "ListNames")
+    }
+
+    /// Returns names that can be started by activation.
+    pub fn list_activatable_names(&mut self) -> Result<Vec<BusName>> {
+        let reply = self.bus_call("ListActivatableNames", &[])?;
+        names(reply, "ListActivatableNames")
+    }
+
+    /// Returns the unique name of the owner of given name, or `None` if the
+    /// name has no owner.
+    pub fn name_owner(&mut self, name: &str) -> Result<Option<BusName>> {
+        let name = Value::String(BusName::new(name)?.as_str().to_owned());
+        match self.bus_call("GetNameOwner", &[name]) {
+            Ok(reply) => match reply.first().and_then(Value::as_str) {
+                Some(owner) => Ok(Some(BusName::new(owner)?)),
+                None => Err(invalid_reply("GetNameOwner")),
+            },
+            Err(e) => match MethodError::from_io(&e) {
+                Some(e) if e.name() == "org.freedesktop.DBus.Error.NameHasNoOwner" => {
+                    Ok(None)
+                }
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Returns credentials of the connection owning given name.
+    pub fn connection_credentials(
+        &mut self,
+        name: &str,
+    ) -> Result<ConnectionCredentials> {
+        let name = Value::String(BusName::new(name)?.as_str().to_owned());
+        let reply = self.bus_call("GetConnectionCredentials", &[name])?;
+        let entries = match reply.first() {
+            Some(Value::Array(_, entries)) => entries,
+            _ => return Err(invalid_reply("GetConnectionCredentials")),
+        };
+        let mut credentials = ConnectionCredentials::default();
+        for entry in entries {
+            let (key, value) = match entry {
+                Value::DictEntry(key, value) => match (&**key, &**value) {
+                    (Value::String(key), Value::Variant(value)) => (key, &**value),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            match (key.as_str(), value) {
+                ("UnixUserID", &Value::Uint32(uid)) => {
+                    credentials.unix_user_id = Some(uid)
+                }
+                ("UnixGroupIDs", Value::Array(_, gids)) => {
+                    credentials.unix_group_ids = Some(
+                        gids.iter()
+                            .filter_map(|gid| match *gid {
+                                Value::Uint32(gid) => Some(gid),
+                                _ => None,
+                            })
+                            .collect(),
+                    )
+                }
+                ("ProcessID", &Value::Uint32(pid)) => credentials.process_id = Some(pid),
+                ("LinuxSecurityLabel", Value::Array(_, label)) => {
+                    let mut label: Vec<u8> = label
+                        .iter()
+                        .filter_map(|b| match *b {
+                            Value::Byte(b) => Some(b),
+                            _ => None,
+                        })
+                        .collect();
+                    // The label is nul terminated.
+                    if label.last() == Some(&0) {
+                        label.pop();
+                    }
+                    credentials.linux_security_label = Some(label);
+                }
+                // Other credentials are not supported.
+                _ => {}
+            }
+        }
+        Ok(credentials)
+    }
+
+    /// Returns optional features supported by the message bus, as described
+    /// by its `Features` property.
+    pub fn features(&mut self) -> Result<Vec<String>> {
+        let args = [
+            Value::String(BUS_NAME.to_owned()),
+            Value::String("Features".to_owned()),
+        ];
+        let reply = self.call(
+            BUS_NAME,
+            BUS_PATH,
+            "org.freedesktop.DBus.Properties",
+            "Get",
+            &args,
+        )?;
+        match reply.first() {
+            Some(Value::Variant(value)) => match &**value {
+                Value::Array(_, features) => Ok(features
+                    .iter()
+                    .filter_map(|f| f.as_str().map(str::to_owned))
+                    .collect()),
+                _ => Err(invalid_reply("Get")),
+            },
+            _ => Err(invalid_reply("Get")),
+        }
+    }
+
+    /// Requests the bus to start a service by activation, and waits until it
+    /// acquires the name or fails to start.
+    ///
+    /// Errors reported by the bus, e.g., when the service is unknown or
+    /// exits during startup, are returned as [`StartReply::Failed`].
+    pub fn start_service(&mut self, name: &str) -> Result<StartReply> {
+        if BusName::new(name)?.is_unique() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unique name {} cannot be activated", name),
+            ));
+        }
+        let args = [Value::String(name.to_owned()), Value::Uint32(0)];
+        match self.bus_call("StartServiceByName", &args) {
+            Ok(reply) => match reply.first() {
+                Some(&Value::Uint32(1)) => Ok(StartReply::Started),
+                Some(&Value::Uint32(2)) => Ok(StartReply::AlreadyRunning),
+                _ => Err(invalid_reply("StartServiceByName")),
+            },
+            Err(e) => match MethodError::from_io(&e) {
+                Some(error) => Ok(StartReply::Failed(error.clone())),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Asks the bus to route messages matching given rule to the connection.
+    pub(crate) fn add_match(&mut self, rule: &str) -> Result<()> {
+        self.bus_call("AddMatch", &[Value::String(rule.to_owned())])?;
+        Ok(())
+    }
+
+    /// Turns the connection into a monitor, which receives copies of all
+    /// messages on the bus.
+    pub(crate) fn become_monitor(&mut self) -> Result<()> {
+        let args = [Value::Array("s".to_owned(), Vec::new()), Value::Uint32(0)];
+        self.call(
+            BUS_NAME,
+            BUS_PATH,
+            "org.freedesktop.DBus.Monitoring",
+            "BecomeMonitor",
+            &args,
+        )?;
+        self.queue.clear();
+        Ok(())
+    }
+
+    /// Calls a method of the message bus interface.
+    fn bus_call(&mut self, member: &str, args: &[Value]) -> Result<Vec<Value>> {
+        self.call(BUS_NAME, BUS_PATH, BUS_NAME, member, args)
+    }
+
+    /// Sends a method call and returns the body of the reply. Error replies
+    /// are converted into errors.
+    pub(crate) fn method_call(&mut self, message: Message) -> Result<Vec<Value>> {
+        let reply = self.call_message(message)?;
+        match reply.message_type {
+            MessageType::Error => Err(error_from_reply(&reply)),
+            _ => Ok(reply.body),
+        }
+    }
+}
+
+/// Converts an error reply into an error.
+pub(crate) fn error_from_reply(reply: &Message) -> Error {
+    Error::other(MethodError {
+        name: reply.error_name.clone().unwrap_or_default(),
+        message: reply
+            .body
+            .first()
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+    })
+}
+
+fn invalid_reply(member: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid reply to {}", member),
+    )
+}
+
+/// Converts an array of strings into bus names.
+fn names(reply: Vec<Value>, member: &str) -> Result<Vec<BusName>> {
+    match reply.into_iter().next() {
+        Some(Value::Array(_, names)) => names
+            .iter()
+            .map(|name| match name.as_str() {
+                Some(name) => BusName::new(name),
+                None => Err(invalid_reply(member)),
+            })
+            .collect(),
+        _ => Err(invalid_reply(member)),
+    }
+}
+
+/// An error reply to a method call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MethodError {
+    name: String,
+    message: Option<String>,
+}
+
+impl MethodError {
+    /// Returns the error reply carried by an error returned from
+    /// [`Connection`] methods, if any.
+    pub fn from_io(error: &Error) -> Option<&MethodError> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// Returns the error name, e.g., `org.freedesktop.DBus.Error.Failed`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the error message, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+}
+
+impl fmt::Display for MethodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.message {
+            Some(ref message) => write!(f, "{}: {}", self.name, message),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+impl std::error::Error for MethodError {}
+
+/// A reply to a request to start a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StartReply {
+    /// The service was started and acquired the name.
+    Started,
+    /// The name already had an owner.
+    AlreadyRunning,
+    /// The service could not be started, e.g., with
+    /// `org.freedesktop.DBus.Error.ServiceUnknown`.
+    Failed(MethodError),
+}
+
+/// Credentials of a connection, as reported by the message bus.
+///
+/// Credentials the bus does not know about are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionCredentials {
+    unix_user_id: Option<u32>,
+    unix_group_ids: Option<Vec<u32>>,
+    process_id: Option<u32>,
+    linux_security_label: Option<Vec<u8>>,
+}
+
+impl ConnectionCredentials {
+    /// Returns the user ID of the process.
+    pub fn unix_user_id(&self) -> Option<u32> {
+        self.unix_user_id
+    }
+
+    /// Returns the group IDs of the process.
+    pub fn unix_group_ids(&self) -> Option<&[u32]> {
+        self.unix_group_ids.as_deref()
+    }
+
+    /// Returns the PID of the process.
+    pub fn process_id(&self) -> Option<u32> {
+        self.process_id
+    }
+
+    /// Returns the security label of the process, without the terminating
+    /// nul byte.
+    pub fn linux_security_label(&self) -> Option<&[u8]> {
+        self.linux_security_label.as_deref()
+    }
+}
+
+/// Reports timeouts consistently, since an expired socket timeout is
+/// reported as either EAGAIN or EWOULDBLOCK.
+fn timed_out(error: Error) -> Error {
+    match error.kind() {
+        ErrorKind::WouldBlock => Error::new(ErrorKind::TimedOut, "operation timed out"),
+        _ => error,
+    }
+}
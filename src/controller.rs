@@ -0,0 +1,332 @@
+//! A controller for dbus-broker.
+//!
+//! Implements the part of `org.bus1.DBus.Controller` protocol that is
+//! otherwise provided by dbus-broker-launch: adding a listener socket together
+//! with a policy, registering activatable names and spawning services when
+//! broker requests their activation.
+
+use crate::client::Connection;
+use crate::message::{Message, Value, FLAG_NO_REPLY_EXPECTED};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::OwnedFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+
+const BROKER_PATH: &str = "/org/bus1/DBus/Broker";
+const BROKER_INTERFACE: &str = "org.bus1.DBus.Broker";
+const NAME_PATH: &str = "/org/bus1/DBus/Name/";
+const NAME_INTERFACE: &str = "org.bus1.DBus.Name";
+const LISTENER_PATH: &str = "/org/bus1/DBus/Listener/";
+
+/// A service that can be started using D-Bus activation.
+#[derive(Clone, Debug)]
+pub(crate) struct Activatable {
+    pub(crate) name: String,
+    pub(crate) argv: Vec<OsString>,
+}
+
+/// Environment of activated services.
+#[derive(Clone, Debug)]
+pub(crate) struct Environment {
+    pub(crate) address: String,
+    /// Process group of the broker, which services join.
+    pub(crate) process_group: libc::pid_t,
+    /// User owning the activatable names.
+    pub(crate) uid: u32,
+    pub(crate) bus_type: Option<&'static str>,
+}
+
+/// Configures the broker and starts a thread handling activation requests.
+///
+/// The thread finishes once the broker closes the controller connection.
+pub(crate) fn start(
+    mut conn: Connection,
+    listeners: Vec<OwnedFd>,
+    services: Vec<Activatable>,
+    env: Environment,
+) -> Result<JoinHandle<()>> {
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let mut add_listener =
+            Message::method_call(None, BROKER_PATH, BROKER_INTERFACE, "AddListener")
+                .arg(Value::ObjectPath(format!("{}{}", LISTENER_PATH, i)))
+                .arg(Value::UnixFd(0))
+                .arg(Value::Variant(Box::new(policy())));
+        add_listener.fds.push(listener);
+        conn.method_call(add_listener)?;
+    }
+
+    for (i, service) in services.iter().enumerate() {
+        let add_name =
+            Message::method_call(None, BROKER_PATH, BROKER_INTERFACE, "AddName")
+                .arg(Value::ObjectPath(format!("{}{}", NAME_PATH, i)))
+                .arg(Value::String(service.name.clone()))
+                .arg(Value::Uint32(env.uid));
+        conn.method_call(add_name)?;
+    }
+
+    let controller = Controller {
+        conn,
+        services,
+        env,
+        activation_env: HashMap::new(),
+        children: Vec::new(),
+    };
+    thread::Builder::new()
+        .name("dbus-broker-controller".to_owned())
+        .spawn(move || controller.run())
+}
+
+struct Controller {
+    conn: Connection,
+    services: Vec<Activatable>,
+    env: Environment,
+    /// Environment set with UpdateActivationEnvironment.
+    activation_env: HashMap<String, String>,
+    /// Activated services that were not reaped yet.
+    children: Vec<Child>,
+}
+
+impl Controller {
+    fn run(mut self) {
+        while let Ok(message) = self.conn.recv() {
+            if message.is(NAME_INTERFACE, "Activate") {
+                self.activate(&message);
+            } else if message.is(BROKER_INTERFACE, "SetActivationEnvironment") {
+                if let Some(Value::Array(_, entries)) = message.body.first() {
+                    for entry in entries {
+                        if let Value::DictEntry(key, value) = entry {
+                            if let (Some(key), Some(value)) =
+                                (key.as_str(), value.as_str())
+                            {
+                                self.activation_env
+                                    .insert(key.to_owned(), value.to_owned());
+                            }
+                        }
+                    }
+                }
+            } else if message.message_type == crate::message::MessageType::MethodCall {
+                let reply = Message::error(
+                    &message,
+                    "org.freedesktop.DBus.Error.UnknownMethod",
+                    "Unknown method",
+                );
+                if self.conn.send(reply).is_err() {
+                    break;
+                }
+            }
+            self.children
+                .retain_mut(|child| !matches!(child.try_wait(), Ok(Some(_))));
+        }
+
+        for mut child in self.children {
+            let _ = child.wait();
+        }
+    }
+
+    fn activate(&mut self, message: &Message) {
+        let serial = match message.body.first() {
+            Some(&Value::Uint64(serial)) => serial,
+            _ => return,
+        };
+        let service = message
+            .path
+            .as_deref()
+            .and_then(|path| path.strip_prefix(NAME_PATH))
+            .and_then(|index| index.parse::<usize>().ok())
+            .and_then(|index| self.services.get(index));
+
+        let spawned = match service {
+            Some(service) => {
+                let mut command = Command::new(&service.argv[0]);
+                command
+                    .args(&service.argv[1..])
+                    .stdin(Stdio::null())
+                    .process_group(self.env.process_group)
+                    .envs(&self.activation_env)
+                    .env("DBUS_STARTER_ADDRESS", &self.env.address);
+                if let Some(bus_type) = self.env.bus_type {
+                    command.env("DBUS_STARTER_BUS_TYPE", bus_type);
+                }
+                command.spawn()
+            }
+            None => Err(Error::from(ErrorKind::NotFound)),
+        };
+
+        match spawned {
+            Ok(child) => self.children.push(child),
+            Err(_) => {
+                let mut reset = Message::method_call(
+                    None,
+                    message.path.as_deref().unwrap_or_default(),
+                    NAME_INTERFACE,
+                    "Reset",
+                )
+                .arg(Value::Uint64(serial));
+                reset.flags |= FLAG_NO_REPLY_EXPECTED;
+                let _ = self.conn.send(reset);
+            }
+        }
+    }
+}
+
+/// Returns a policy equivalent to the default policy of the daemon config
+/// file: connections are accepted, and everyone is allowed to own any name
+/// and to send and receive any message.
+fn policy() -> Value {
+    let allow_all = || {
+        Value::Struct(vec![
+            Value::Boolean(true),
+            Value::Uint64(1),
+            Value::String(String::new()),
+            Value::String(String::new()),
+            Value::String(String::new()),
+            Value::String(String::new()),
+            Value::Uint32(0),
+            Value::Uint32(0),
+            Value::Uint64(0),
+            Value::Uint64(u64::MAX),
+        ])
+    };
+    let batch = Value::Struct(vec![
+        // Connect.
+        Value::Boolean(true),
+        Value::Uint64(1),
+        // Own.
+        Value::Array(
+            "(btbs)".to_owned(),
+            vec![Value::Struct(vec![
+                Value::Boolean(true),
+                Value::Uint64(1),
+                Value::Boolean(true),
+                Value::String(String::new()),
+            ])],
+        ),
+        // Send.
+        Value::Array("(btssssuutt)".to_owned(), vec![allow_all()]),
+        // Receive.
+        Value::Array("(btssssuutt)".to_owned(), vec![allow_all()]),
+    ]);
+    let batch_signature = batch.signature();
+    Value::Struct(vec![
+        // Per-uid policy, with uid -1 used for the default context.
+        Value::Array(
+            format!("(u{})", batch_signature),
+            vec![Value::Struct(vec![Value::Uint32(u32::MAX), batch])],
+        ),
+        // Per-gid policy.
+        Value::Array(format!("(buu{})", batch_signature), vec![]),
+        // SELinux contexts.
+        Value::Array("(ss)".to_owned(), vec![]),
+        // AppArmor.
+        Value::Boolean(false),
+        // Bus type.
+        Value::String(String::new()),
+    ])
+}
+
+/// Reads activatable services from .service files in given directory.
+pub(crate) fn read_service_dir(dir: &Path) -> Result<Vec<Activatable>> {
+    let mut services = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(services),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "service") {
+            continue;
+        }
+        let contents = fs::read_to_string(&path)?;
+        let mut name = None;
+        let mut exec = None;
+        for line in contents.lines() {
+            if let Some(value) = line.strip_prefix("Name=") {
+                name = Some(value.trim().to_owned());
+            } else if let Some(value) = line.strip_prefix("Exec=") {
+                exec = Some(split_exec(value.trim()));
+            }
+        }
+        // Services without Exec, e.g., those activated through systemd
+        // only, cannot be activated by the controller.
+        if let (Some(name), Some(argv)) = (name, exec) {
+            if !argv.is_empty() {
+                services.push(Activatable { name, argv });
+            }
+        }
+    }
+    Ok(services)
+}
+
+/// Splits Exec line into arguments, honoring quotes and backslash escapes.
+fn split_exec(exec: &str) -> Vec<OsString> {
+    let mut argv = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => argv.extend(arg.take()),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (Some(q), c) if q == c => quote = None,
+            (Some('\''), c) => arg.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let arg = arg.get_or_insert_with(String::new);
+                arg.extend(chars.next());
+            }
+            (_, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    argv.extend(arg);
+    argv.into_iter().map(OsString::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |file: &str, contents: &str| {
+            fs::write(dir.path().join(file), contents).unwrap();
+        };
+        write(
+            "com.example.A.service",
+            "[D-BUS Service]\nName=com.example.A\nExec=/usr/bin/false --a\n",
+        );
+        write(
+            "com.example.B.service",
+            "[D-BUS Service]\nName=com.example.B\nExec=/bin/false\n\
+             SystemdService=b.service\n",
+        );
+        write(
+            "com.example.Systemd.service",
+            "[D-BUS Service]\nName=com.example.Systemd\nSystemdService=c.service\n",
+        );
+        write("README", "Name=com.example.Ignored\nExec=/bin/false\n");
+
+        let mut services = read_service_dir(dir.path()).unwrap();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        let names: Vec<_> = services.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec!["com.example.A", "com.example.B"], names);
+        assert_eq!(vec!["/usr/bin/false", "--a"], services[0].argv);
+    }
+
+    #[test]
+    fn exec() {
+        assert_eq!(vec!["/usr/bin/false"], split_exec("/usr/bin/false"));
+        assert_eq!(
+            vec!["/bin/a b", "--x", "it's", "\"", ""],
+            split_exec(r#""/bin/a b"  --x 'it'\''s' \" ''"#)
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Observing changes of name owners on the bus.
+
+use crate::client::Connection;
+use crate::message::{MessageType, Value};
+use crate::BusName;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+/// Match rule selecting NameOwnerChanged signals emitted by the bus.
+const MATCH_RULE: &str = "type='signal',sender='org.freedesktop.DBus',\
+                          interface='org.freedesktop.DBus',member='NameOwnerChanged'";
+
+/// A change of the owner of a name on the bus.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameOwnerChanged {
+    name: BusName,
+    old_owner: Option<BusName>,
+    new_owner: Option<BusName>,
+}
+
+impl NameOwnerChanged {
+    /// Returns the name whose owner changed.
+    pub fn name(&self) -> &BusName {
+        &self.name
+    }
+
+    /// Returns the unique name of the previous owner, or `None` if the name
+    /// had no owner.
+    pub fn old_owner(&self) -> Option<&BusName> {
+        self.old_owner.as_ref()
+    }
+
+    /// Returns the unique name of the new owner, or `None` if the name was
+    /// released.
+    pub fn new_owner(&self) -> Option<&BusName> {
+        self.new_owner.as_ref()
+    }
+}
+
+/// Changes of name owners on the bus, in order they happened.
+///
+/// Created with [`Daemon::name_events`]. All changes after its creation are
+/// received, since they are queued by the bus until read. Iteration ends
+/// when the bus closes the connection.
+///
+/// [`Daemon::name_events`]: crate::Daemon::name_events
+#[derive(Debug)]
+pub struct NameEvents {
+    conn: Connection,
+}
+
+impl NameEvents {
+    /// Connects to the bus at given address and subscribes to changes.
+    pub(crate) fn open(address: &str) -> Result<NameEvents> {
+        let mut conn = Connection::open(address)?;
+        conn.add_match(MATCH_RULE)?;
+        Ok(NameEvents { conn })
+    }
+
+    /// Waits for the next change.
+    pub fn recv(&mut self) -> Result<NameOwnerChanged> {
+        self.conn.set_timeout(None)?;
+        self.next_event()
+    }
+
+    /// Waits at most `timeout` for the next change.
+    ///
+    /// Fails with [`ErrorKind::TimedOut`] if there is no change in time.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<NameOwnerChanged> {
+        self.wait_for(timeout, |_| true)
+    }
+
+    /// Waits at most `timeout` for a change for which `predicate` returns
+    /// true, and returns it. Changes before it are discarded.
+    ///
+    /// Fails with [`ErrorKind::TimedOut`] if there is no such change in time.
+    pub fn wait_for<F>(
+        &mut self,
+        timeout: Duration,
+        mut predicate: F,
+    ) -> Result<NameOwnerChanged>
+    where
+        F: FnMut(&NameOwnerChanged) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                return Err(Error::new(ErrorKind::TimedOut, "operation timed out"));
+            }
+            self.conn.set_timeout(Some(remaining))?;
+            let event = self.next_event()?;
+            if predicate(&event) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Waits at most `timeout` until given name is acquired, and returns the
+    /// unique name of its new owner.
+    pub fn wait_for_owner(&mut self, name: &str, timeout: Duration) -> Result<BusName> {
+        BusName::new(name)?;
+        let event = self.wait_for(timeout, |event| {
+            event.name.as_str() == name && event.new_owner.is_some()
+        })?;
+        Ok(event.new_owner.unwrap())
+    }
+
+    /// Waits at most `timeout` until given name is released, and returns the
+    /// unique name of its previous owner.
+    pub fn wait_for_release(
+        &mut self,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<BusName> {
+        BusName::new(name)?;
+        let event = self.wait_for(timeout, |event| {
+            event.name.as_str() == name && event.old_owner.is_some()
+        })?;
+        Ok(event.old_owner.unwrap())
+    }
+
+    /// Receives messages until a change is found.
+    fn next_event(&mut self) -> Result<NameOwnerChanged> {
+        loop {
+            let message = self.conn.recv()?;
+            if message.message_type != MessageType::Signal
+                || message.sender.as_deref() != Some("org.freedesktop.DBus")
+                || !message.is("org.freedesktop.DBus", "NameOwnerChanged")
+            {
+                continue;
+            }
+            let owner = |value: &Value| match value.as_str() {
+                Some("") => Ok(None),
+                Some(owner) => BusName::new(owner).map(Some),
+                None => Err(invalid_signal()),
+            };
+            return match &message.body[..] {
+                [name, old_owner, new_owner] => Ok(NameOwnerChanged {
+                    name: BusName::new(name.as_str().ok_or_else(invalid_signal)?)?,
+                    old_owner: owner(old_owner)?,
+                    new_owner: owner(new_owner)?,
+                }),
+                _ => Err(invalid_signal()),
+            };
+        }
+    }
+}
+
+impl Iterator for NameEvents {
+    type Item = Result<NameOwnerChanged>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.recv() {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => None,
+            result => Some(result),
+        }
+    }
+}
+
+fn invalid_signal() -> Error {
+    Error::new(ErrorKind::InvalidData, "invalid NameOwnerChanged signal")
+}
@@ -0,0 +1,148 @@
+//! Detection of connections and match rules left over by clients.
+
+use crate::BusName;
+use std::fmt;
+use std::io::Error;
+
+/// Connections and match rules on the bus at some point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Snapshot {
+    /// Unique names of connections.
+    pub(crate) connections: Vec<BusName>,
+    /// Match rules with unique names of connections that added them, or
+    /// `None` if the bus does not provide statistics.
+    pub(crate) match_rules: Option<Vec<(BusName, String)>>,
+}
+
+impl Snapshot {
+    /// Returns connections and match rules not present in the baseline, or
+    /// `None` if there are none.
+    pub(crate) fn leaks_since(&self, baseline: &Snapshot) -> Option<Leaks> {
+        let connections: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|name| !baseline.connections.contains(name))
+            .cloned()
+            .collect();
+        let mut match_rules = Vec::new();
+        if let (Some(rules), Some(baseline)) = (&self.match_rules, &baseline.match_rules)
+        {
+            // The same rule may be added multiple times.
+            let mut baseline = baseline.clone();
+            for rule in rules {
+                match baseline.iter().position(|r| r == rule) {
+                    Some(i) => {
+                        baseline.swap_remove(i);
+                    }
+                    None => match_rules.push(rule.clone()),
+                }
+            }
+        }
+        if connections.is_empty() && match_rules.is_empty() {
+            None
+        } else {
+            Some(Leaks {
+                connections,
+                match_rules,
+            })
+        }
+    }
+}
+
+/// Connections and match rules left over on the bus, as detected when
+/// [`Launcher::check_leaks`] is enabled.
+///
+/// [`Launcher::check_leaks`]: crate::Launcher::check_leaks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Leaks {
+    connections: Vec<BusName>,
+    match_rules: Vec<(BusName, String)>,
+}
+
+impl Leaks {
+    /// Returns the leaks carried by an error returned from
+    /// [`Daemon::stop`], if any.
+    ///
+    /// [`Daemon::stop`]: crate::Daemon::stop
+    pub fn from_io(error: &Error) -> Option<&Leaks> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// Returns unique names of connections left over.
+    pub fn connections(&self) -> &[BusName] {
+        &self.connections
+    }
+
+    /// Returns match rules left over, with unique names of connections that
+    /// added them.
+    pub fn match_rules(&self) -> &[(BusName, String)] {
+        &self.match_rules
+    }
+}
+
+impl fmt::Display for Leaks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "leaked {} connection(s) and {} match rule(s)",
+            self.connections.len(),
+            self.match_rules.len()
+        )?;
+        for name in &self.connections {
+            write!(f, "\n  connection {}", name)?;
+        }
+        for (name, rule) in &self.match_rules {
+            write!(f, "\n  match rule of {}: {}", name, rule)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Leaks {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> BusName {
+        BusName::new(name).unwrap()
+    }
+
+    #[test]
+    fn leaks_since() {
+        let rule = "type='signal'".to_owned();
+        let baseline = Snapshot {
+            connections: vec![name(":1.1")],
+            match_rules: Some(vec![(name(":1.1"), rule.clone())]),
+        };
+        assert_eq!(None, baseline.leaks_since(&baseline));
+
+        let current = Snapshot {
+            connections: vec![name(":1.1"), name(":1.2")],
+            match_rules: Some(vec![
+                (name(":1.1"), rule.clone()),
+                (name(":1.1"), rule.clone()),
+            ]),
+        };
+        let leaks = current.leaks_since(&baseline).unwrap();
+        assert_eq!(&[name(":1.2")], leaks.connections());
+        assert_eq!(&[(name(":1.1"), rule.clone())], leaks.match_rules());
+        assert_eq!(
+            "leaked 1 connection(s) and 1 match rule(s)\n  \
+             connection :1.2\n  \
+             match rule of :1.1: type='signal'",
+            leaks.to_string()
+        );
+
+        // Match rules are not compared without statistics.
+        let current = Snapshot {
+            match_rules: None,
+            ..current
+        };
+        let leaks = current.leaks_since(&baseline).unwrap();
+        assert!(leaks.match_rules().is_empty());
+
+        // Closed connections are not leaks.
+        assert_eq!(None, Snapshot::default().leaks_since(&baseline));
+    }
+}
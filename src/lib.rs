@@ -36,22 +36,60 @@
 //!
 //! ```
 
+use crate::address::{Address, UnixAddress};
+use crate::client::Connection;
 use crate::process::Process;
 use crate::xml::XmlWriter;
 use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
 use std::os::unix::ffi::*;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 use std::time::Duration;
 
+mod activation;
+mod address;
+pub mod client;
+mod controller;
+mod events;
+mod leaks;
+mod message;
+mod monitor;
+mod names;
 mod pipe;
 mod process;
+mod proxy;
+mod sandbox;
+mod service;
+mod stats;
 mod sys;
 mod xml;
 
+pub use crate::activation::Activation;
+pub use crate::events::{NameEvents, NameOwnerChanged};
+pub use crate::leaks::Leaks;
+pub use crate::names::{BusName, InterfaceName};
+pub use crate::proxy::FaultConfig;
+pub use crate::sandbox::Sandbox;
+pub use crate::service::service_main;
+pub use crate::stats::{ConnectionStats, Stats};
+#[doc(hidden)]
+pub use crate::sys::{close_on_exec_using, CloseOnExec};
+
+/// Maximum time to wait for a daemon to become ready after exec.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum time to wait for a reply from the bus to a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum time to wait for the bus to notice connections closed just before
+/// checking for leaks.
+const LEAK_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// A D-Bus daemon launcher.
 #[derive(Clone, Debug)]
 pub struct Launcher {
@@ -59,32 +97,56 @@ pub struct Launcher {
     daemon_type: DaemonType,
     config: Config,
     services: Vec<Service>,
+    sockets: Vec<Arc<OwnedFd>>,
+    fds: Vec<(RawFd, Arc<OwnedFd>)>,
+    current_dir: Option<PathBuf>,
+    umask: Option<u32>,
+    rlimits: Vec<(Resource, u64, u64)>,
+    credentials: process::Credentials,
+    sandbox: Option<Sandbox>,
+    monitor: Option<PathBuf>,
+    check_leaks: bool,
 }
 
 #[derive(Clone, Debug, Default)]
 struct Config {
     bus_type: Option<BusType>,
+    user: Option<String>,
+    keep_umask: bool,
     allow_anonymous: bool,
+    allow_stats: bool,
     listen: Vec<String>,
     auth: Vec<Auth>,
     service_dirs: Vec<PathBuf>,
 }
 
-
 /// A type of a D-Bus daemon.
 #[non_exhaustive]
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DaemonType {
     /// A dbus-daemon from the reference implementation.
     DBusDaemon,
-    /// A dbus-broker.
+    /// A dbus-broker started through dbus-broker-launch.
     DBusBroker,
+    /// A dbus-broker started directly, with the launcher acting as its
+    /// controller.
+    ///
+    /// Services are activated by the launcher. The configuration file is not
+    /// used, instead the broker is set up with a policy equivalent to the
+    /// default one.
+    DBusBrokerDirect,
 }
 
 #[derive(Clone, Debug)]
 struct Service {
     name: String,
-    exec: PathBuf,
+    exec: Exec,
+}
+
+#[derive(Clone, Debug)]
+enum Exec {
+    Path(PathBuf),
+    Fn,
 }
 
 /// A running D-Bus daemon process.
@@ -95,6 +157,35 @@ pub struct Daemon {
     address: String,
     tmp_dir: tempfile::TempDir,
     process: Process,
+    bus_pid: libc::pid_t,
+    controller: Option<JoinHandle<()>>,
+    /// Connection used to query the bus, opened on first use.
+    conn: Mutex<Option<client::Connection>>,
+    monitors: Vec<monitor::Monitor>,
+    /// Capture file in the temporary directory started with start_monitor.
+    capture: Option<PathBuf>,
+    /// Connections and match rules after launch, if leaks are checked.
+    leak_baseline: Option<leaks::Snapshot>,
+    proxies: Vec<proxy::Proxy>,
+}
+
+/// A process started by the daemon.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessInfo {
+    pid: libc::pid_t,
+    name: String,
+}
+
+impl ProcessInfo {
+    /// Returns the PID of the process.
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Returns the name of the process executable, possibly truncated.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 }
 
 /// An authentication mechanism.
@@ -105,6 +196,27 @@ pub enum Auth {
     DBusCookieSha1,
 }
 
+/// A resource whose consumption can be limited with
+/// [`Launcher::rlimit`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resource {
+    /// Maximum size of the virtual memory in bytes (`RLIMIT_AS`).
+    AddressSpace,
+    /// Maximum size of a core file in bytes (`RLIMIT_CORE`).
+    Core,
+    /// CPU time limit in seconds (`RLIMIT_CPU`).
+    Cpu,
+    /// Maximum size of the data segment in bytes (`RLIMIT_DATA`).
+    Data,
+    /// Maximum size of a created file in bytes (`RLIMIT_FSIZE`).
+    FileSize,
+    /// One more than the maximum file descriptor number (`RLIMIT_NOFILE`).
+    NoFile,
+    /// Maximum size of the stack in bytes (`RLIMIT_STACK`).
+    Stack,
+}
+
 /// A well-known message bus type.
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum BusType {
@@ -120,6 +232,15 @@ impl Launcher {
             daemon_type,
             config: Config::default(),
             services: Vec::default(),
+            sockets: Vec::new(),
+            fds: Vec::new(),
+            current_dir: None,
+            umask: None,
+            rlimits: Vec::new(),
+            credentials: process::Credentials::default(),
+            sandbox: None,
+            monitor: None,
+            check_leaks: false,
         }
     }
 
@@ -144,6 +265,9 @@ impl Launcher {
     /// By default daemon will listen on a Unix domain socket in a temporary
     /// directory.
     ///
+    /// The dbus-broker supports only Unix domain socket addresses with `path`,
+    /// `dir`, `tmpdir` or `abstract` key. Launch fails for other addresses.
+    ///
     /// # Examples
     ///
     /// ```no_run
@@ -156,6 +280,37 @@ impl Launcher {
         self
     }
 
+    /// Listen on an already bound Unix domain socket.
+    ///
+    /// The socket is passed to the daemon using systemd socket activation
+    /// protocol. Binding it in advance makes the address known before the
+    /// launch and the daemon reachable as soon as it starts.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::os::unix::net::UnixListener;
+    ///
+    /// let listener = UnixListener::bind("/tmp/test-bus").unwrap();
+    /// let daemon = dbus_launch::Launcher::daemon()
+    ///     .listen_socket(listener)
+    ///     .launch()
+    ///     .expect("failed to launch dbus-daemon");
+    /// ```
+    pub fn listen_socket(&mut self, listener: UnixListener) -> &mut Self {
+        self.listen_fd(listener)
+    }
+
+    /// Listen on an already bound socket given as a file descriptor.
+    ///
+    /// Like [`listen_socket`](Launcher::listen_socket), but accepts any
+    /// socket supported by the daemon, e.g., a TCP socket for dbus-daemon.
+    /// The dbus-broker supports only Unix domain sockets.
+    pub fn listen_fd<F: Into<OwnedFd>>(&mut self, fd: F) -> &mut Self {
+        self.sockets.push(Arc::new(fd.into()));
+        self
+    }
+
     /// Authorize connections using anonymous mechanism.
     ///
     /// This option has no practical effect unless the anonymous mechanism is
@@ -165,6 +320,13 @@ impl Launcher {
         self
     }
 
+    /// Allow calling methods of the `org.freedesktop.DBus.Debug.Stats`
+    /// interface, which are used by [`Daemon::stats`].
+    pub fn allow_stats(&mut self) -> &mut Self {
+        self.config.allow_stats = true;
+        self
+    }
+
     /// Allow authorization mechanism.
     ///
     /// By default all known mechanisms are allowed.
@@ -188,47 +350,401 @@ impl Launcher {
     }
 
     /// Adds a service file with given name and executable path.
-    pub fn service<P: AsRef<Path>>(&mut self, name: &str, exec: P) -> &mut Self {
-        let name = name.to_string();
-        let exec = exec.as_ref().to_path_buf();
+    ///
+    /// The name must be a valid well-known [`BusName`], otherwise the launch
+    /// fails.
+    pub fn service<N, P>(&mut self, name: N, exec: P) -> &mut Self
+    where
+        N: AsRef<str>,
+        P: AsRef<Path>,
+    {
+        let name = name.as_ref().to_string();
+        let exec = Exec::Path(exec.as_ref().to_path_buf());
         self.services.push(Service { name, exec });
         self
     }
 
+    /// Adds a service file with given name, which runs the function passed
+    /// under that name to [`service_main`] in a new instance of the current
+    /// executable.
+    ///
+    /// The executable must call [`service_main`] at the beginning of `main`.
+    /// The name must be a valid well-known [`BusName`] with a function
+    /// passed to [`service_main`], otherwise the launch fails.
+    pub fn service_fn<N>(&mut self, name: N) -> &mut Self
+    where
+        N: AsRef<str>,
+    {
+        let name = name.as_ref().to_string();
+        let exec = Exec::Fn;
+        self.services.push(Service { name, exec });
+        self
+    }
+
+    /// Runs the daemon as given user.
+    ///
+    /// The switch happens before exec and requires appropriate privileges.
+    /// The temporary directory with the daemon configuration is made owned by
+    /// the user. For dbus-daemon the configuration also includes a matching
+    /// `<user>` element. Since dbus-daemon cannot serve connections without
+    /// an entry for its user in the user database, the launch fails early
+    /// for users without one.
+    ///
+    /// Unless [`groups`](Launcher::groups) are specified, supplementary
+    /// groups are cleared when running as root.
+    pub fn uid(&mut self, uid: u32) -> &mut Self {
+        self.credentials.uid = Some(uid);
+        self
+    }
+
+    /// Runs the daemon with given primary group.
+    pub fn gid(&mut self, gid: u32) -> &mut Self {
+        self.credentials.gid = Some(gid);
+        self
+    }
+
+    /// Runs the daemon with given supplementary groups.
+    pub fn groups(&mut self, groups: &[u32]) -> &mut Self {
+        self.credentials.groups = Some(groups.to_vec());
+        self
+    }
+
+    /// Passes a file descriptor to the daemon as `child_fd`.
+    ///
+    /// File descriptors starting from 3 are used by the launcher itself: one
+    /// for each socket passed with [`listen_fd`](Launcher::listen_fd), one
+    /// for each listen address when using dbus-broker, and two more when
+    /// using dbus-daemon. The descriptor is not passed to services activated
+    /// by the daemon.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::os::unix::net::UnixStream;
+    ///
+    /// let (ours, theirs) = UnixStream::pair().unwrap();
+    /// let daemon = dbus_launch::Launcher::daemon()
+    ///     .inherit_fd(10, theirs)
+    ///     .launch()
+    ///     .expect("failed to launch dbus-daemon");
+    /// ```
+    pub fn inherit_fd<F: Into<OwnedFd>>(&mut self, child_fd: RawFd, fd: F) -> &mut Self {
+        self.fds.retain(|&(target, _)| target != child_fd);
+        self.fds.push((child_fd, Arc::new(fd.into())));
+        self
+    }
+
+    /// Changes the working directory of the daemon.
+    ///
+    /// When running in a sandbox, the path is resolved inside it.
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the file mode creation mask of the daemon.
+    ///
+    /// For dbus-daemon the configuration also includes `<keep_umask/>`.
+    pub fn umask(&mut self, mask: u32) -> &mut Self {
+        self.umask = Some(mask);
+        self
+    }
+
+    /// Sets the soft and hard limit on consumption of a resource by the
+    /// daemon. Use `u64::MAX` for no limit.
+    ///
+    /// Note that dbus-daemon raises its file descriptor limit at startup if
+    /// it has sufficient privileges.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use dbus_launch::{Launcher, Resource};
+    ///
+    /// let daemon = Launcher::daemon()
+    ///     .rlimit(Resource::NoFile, 64, 64)
+    ///     .launch()
+    ///     .expect("failed to launch dbus-daemon");
+    /// ```
+    pub fn rlimit(&mut self, resource: Resource, soft: u64, hard: u64) -> &mut Self {
+        self.rlimits.retain(|&(r, _, _)| r != resource);
+        self.rlimits.push((resource, soft, hard));
+        self
+    }
+
+    /// Runs the daemon inside a sandbox built from fresh Linux namespaces.
+    ///
+    /// Services activated by the daemon run inside the sandbox as well. The
+    /// [`Daemon::pid`] returns the PID of a process outside the sandbox
+    /// that waits for the daemon and forwards termination signals to it.
+    pub fn sandbox(&mut self, sandbox: Sandbox) -> &mut Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Captures all messages on the bus into a file at given path, starting
+    /// right after the launch.
+    ///
+    /// The file is in pcap format with D-Bus link type, as written by
+    /// `dbus-monitor --pcap`, and can be opened in Wireshark. Recent messages
+    /// are also kept as text, see [`Daemon::trace`].
+    pub fn monitor<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.monitor = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Checks that connections and match rules added to the bus after
+    /// launch are gone before the daemon is stopped.
+    ///
+    /// Leftovers are reported as an error from [`Daemon::stop`] carrying
+    /// [`Leaks`], or as a panic when the daemon is dropped, unless the thread
+    /// is already panicking. Connections of activatable services, monitors
+    /// and the one used by the daemon for queries are not considered. Match
+    /// rules are only checked with a bus providing statistics, see
+    /// [`Daemon::stats`], and calls to the statistics interface are allowed
+    /// as with [`allow_stats`](Launcher::allow_stats).
+    pub fn check_leaks(&mut self) -> &mut Self {
+        self.check_leaks = true;
+        self.config.allow_stats = true;
+        self
+    }
+
+    /// Returns sockets for dbus-broker which, unlike dbus-daemon, is unable
+    /// to create them itself: those passed with
+    /// [`listen_fd`](Launcher::listen_fd) and newly bound ones for each
+    /// listen address. Only Unix domain sockets are supported.
+    fn broker_sockets(
+        &self,
+        config: &Config,
+        tmp_dir: &tempfile::TempDir,
+    ) -> io::Result<(Vec<UnixAddress>, Vec<OwnedFd>)> {
+        let mut sockets = Vec::new();
+        let mut listeners = Vec::new();
+        for socket in &self.sockets {
+            sockets.push(UnixAddress::from_socket(socket)?);
+            listeners.push(socket.try_clone()?);
+        }
+        let mut addresses = config
+            .listen
+            .iter()
+            .map(|listen| UnixAddress::from_listen(listen))
+            .collect::<io::Result<Vec<_>>>()?;
+        if addresses.is_empty() && sockets.is_empty() {
+            addresses.push(UnixAddress::Path(tmp_dir.path().join("socket")));
+        }
+        for address in addresses {
+            listeners.push(OwnedFd::from(address.bind()?));
+            sockets.push(address);
+        }
+        Ok((sockets, listeners))
+    }
+
     #[doc(hidden)]
     pub fn program(&mut self, program: &OsStr) -> &mut Self {
         self.program = Some(program.to_owned());
         self
     }
 
+    /// Checks that the configuration is valid and supported by the daemon
+    /// type.
+    ///
+    /// Called automatically by [`launch`](Launcher::launch).
+    pub fn validate(&self) -> io::Result<()> {
+        let broker = self.daemon_type != DaemonType::DBusDaemon;
+
+        for listen in &self.config.listen {
+            if broker {
+                UnixAddress::from_listen(listen)?;
+            } else {
+                Address::parse(listen)?;
+            }
+        }
+
+        if broker && self.config.allow_anonymous {
+            return Err(unsupported("dbus-broker does not support allow_anonymous"));
+        }
+
+        for auth in &self.config.auth {
+            if broker && *auth != Auth::External {
+                return Err(unsupported(&format!(
+                    "dbus-broker does not support {:?} authentication",
+                    auth
+                )));
+            }
+        }
+
+        for dir in &self.config.service_dirs {
+            if dir.to_str().is_none() {
+                return Err(invalid_input(&format!(
+                    "service directory is not valid UTF-8: {}",
+                    dir.display()
+                )));
+            }
+        }
+
+        for service in &self.services {
+            if BusName::new(&service.name)?.is_unique() {
+                return Err(invalid_input(&format!(
+                    "invalid service name {:?}: unique name cannot be activatable",
+                    service.name
+                )));
+            }
+            match service.exec {
+                Exec::Path(ref exec) => {
+                    if exec.to_str().is_none() {
+                        return Err(invalid_input(&format!(
+                            "service executable is not valid UTF-8: {}",
+                            exec.display()
+                        )));
+                    }
+                }
+                Exec::Fn => service::check(&service.name)?,
+            }
+        }
+
+        if broker {
+            for socket in &self.sockets {
+                UnixAddress::from_socket(socket)?;
+            }
+        }
+
+        let reserved = match self.daemon_type {
+            DaemonType::DBusDaemon => 3..5 + self.sockets.len() as RawFd,
+            DaemonType::DBusBroker => {
+                let sockets = self.config.listen.len() + self.sockets.len();
+                3..3 + sockets.max(1) as RawFd
+            }
+            DaemonType::DBusBrokerDirect => 3..4,
+        };
+        for &(child_fd, _) in &self.fds {
+            if child_fd < 0 || reserved.contains(&child_fd) {
+                return Err(invalid_input(&format!(
+                    "file descriptor {} cannot be passed to the daemon",
+                    child_fd
+                )));
+            }
+        }
+
+        for &(resource, soft, hard) in &self.rlimits {
+            if soft > hard {
+                return Err(invalid_input(&format!(
+                    "soft limit of {:?} exceeds the hard limit",
+                    resource
+                )));
+            }
+        }
+
+        if let (DaemonType::DBusDaemon, Some(uid)) =
+            (self.daemon_type, self.credentials.uid)
+        {
+            if sys::user_name(uid).is_none() {
+                return Err(invalid_input(&format!(
+                    "uid {} has no entry in the user database required by dbus-daemon",
+                    uid
+                )));
+            }
+        }
+
+        if let Some(ref dir) = self.current_dir {
+            if dir.as_os_str().as_bytes().contains(&0) {
+                return Err(invalid_input("current_dir contains a nul byte"));
+            }
+        }
+
+        if let Some(ref sandbox) = self.sandbox {
+            if !cfg!(target_os = "linux") {
+                return Err(unsupported("sandbox is supported on Linux only"));
+            }
+            if self.daemon_type == DaemonType::DBusBrokerDirect {
+                return Err(unsupported(
+                    "sandbox is not supported with directly launched dbus-broker",
+                ));
+            }
+            if !broker {
+                for listen in &self.config.listen {
+                    // Only the configuration directory is writable and
+                    // shared with the host, and its path is not known yet.
+                    if let Ok(UnixAddress::Path(_)) = UnixAddress::from_listen(listen) {
+                        return Err(unsupported(&format!(
+                            "address {:?} is inaccessible from outside of sandbox file system",
+                            listen
+                        )));
+                    }
+                    if sandbox.isolates_network() {
+                        return Err(unsupported(&format!(
+                            "address {:?} is unreachable from outside of sandbox network namespace",
+                            listen
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Starts the dbus-daemon process.
     pub fn launch(&self) -> io::Result<Daemon> {
+        let mut daemon = self.spawn()?;
+        if let Some(ref path) = self.monitor {
+            let monitor = monitor::Monitor::start(&daemon.address, path)?;
+            daemon.monitors.push(monitor);
+        }
+        if self.check_leaks {
+            daemon.leak_baseline = Some(daemon.snapshot()?);
+        }
+        Ok(daemon)
+    }
+
+    fn spawn(&self) -> io::Result<Daemon> {
+        self.validate()?;
+
         let mut config = self.config.clone();
+        if DaemonType::DBusDaemon == self.daemon_type {
+            config.user = self.credentials.uid.and_then(sys::user_name);
+            config.keep_umask = self.umask.is_some();
+        }
 
         // Create temporary dir for configuration files.
         let tmp_dir = tempfile::Builder::new()
             .prefix("dbus-daemon-rs-")
             .tempdir()?;
 
+        if DaemonType::DBusDaemon == self.daemon_type && !self.sockets.is_empty() {
+            config.listen.push("systemd:".to_owned());
+        }
+
         if DaemonType::DBusDaemon == self.daemon_type && config.listen.is_empty() {
             // We use unix:dir instead of unix:tmpdir to avoid using abstract
             // sockets on Linux which are currently poorly supported in Rust
             // ecosystem.
-            let path = escape_path(&tmp_dir.path());
+            let path = escape_path(tmp_dir.path());
             let address = format!("unix:dir={}", &path);
             config.listen.push(address);
         }
 
         // Write service files.
+        let service_argvs = self
+            .services
+            .iter()
+            .map(|service| match service.exec {
+                Exec::Path(ref exec) => Ok(vec![exec.clone().into_os_string()]),
+                Exec::Fn => service::argv(&service.name),
+            })
+            .collect::<io::Result<Vec<_>>>()?;
         if !self.services.is_empty() {
             config.service_dirs.push(tmp_dir.path().to_owned());
-            for service in &self.services {
+            let activation_dir = tmp_dir.path().join(activation::DIR);
+            fs::create_dir(&activation_dir)?;
+            for (service, argv) in self.services.iter().zip(&service_argvs) {
+                let exec =
+                    activation::write_wrapper(&activation_dir, &service.name, argv)?;
                 let file = format!("{}.service", service.name);
                 let path = tmp_dir.path().join(&file);
                 let contents = format!(
                     "[D-BUS Service]\nName={}\nExec={}\n",
                     service.name,
-                    service.exec.display()
+                    exec.display()
                 );
                 fs::write(path, contents)?;
             }
@@ -238,41 +754,188 @@ impl Launcher {
         let config_file = tmp_dir.path().join("daemon.conf");
         fs::write(&config_file, config.to_xml().as_bytes())?;
 
+        if !self.credentials.is_empty() {
+            let uid = self.credentials.uid;
+            let gid = self.credentials.gid;
+            std::os::unix::fs::chown(tmp_dir.path(), uid, gid)?;
+            for entry in fs::read_dir(tmp_dir.path())? {
+                std::os::unix::fs::chown(entry?.path(), uid, gid)?;
+            }
+        }
+
         let program = self.program.as_deref();
+        let mut options = process::Options {
+            fds: self
+                .fds
+                .iter()
+                .map(|(child_fd, fd)| (fd.as_raw_fd(), *child_fd))
+                .collect(),
+            current_dir: self
+                .current_dir
+                .as_ref()
+                .map(|dir| std::ffi::CString::new(dir.as_os_str().as_bytes()).unwrap()),
+            umask: self.umask.map(|mask| (mask & 0o777) as libc::mode_t),
+            rlimits: self
+                .rlimits
+                .iter()
+                .map(|&(resource, soft, hard)| (resource.to_raw(), rlimit(soft, hard)))
+                .collect(),
+            credentials: self.credentials.clone(),
+            sandbox: None,
+        };
+        if let Some(ref sandbox) = self.sandbox {
+            let root = tmp_dir.path().join("sandbox");
+            fs::create_dir(&root)?;
+            let mut paths: Vec<&Path> = Vec::new();
+            paths.extend(service_argvs.iter().map(|argv| Path::new(&argv[0])));
+            paths.extend(self.config.service_dirs.iter().map(|d| d.as_path()));
+            let program = program.unwrap_or(OsStr::new(match self.daemon_type {
+                DaemonType::DBusDaemon => "dbus-daemon",
+                _ => "dbus-broker-launch",
+            }));
+            let uid = self
+                .credentials
+                .uid
+                .unwrap_or_else(|| unsafe { libc::getuid() });
+            let gid = self
+                .credentials
+                .gid
+                .unwrap_or_else(|| unsafe { libc::getgid() });
+            options.sandbox = Some(sandbox.prepare(
+                uid,
+                gid,
+                program,
+                &root,
+                tmp_dir.path(),
+                &paths,
+            )?);
+        }
         match self.daemon_type {
             DaemonType::DBusDaemon => {
-                let (process, address) =
-                    Process::spawn_dbus_daemon(program, &config_file)?;
+                let sockets: Vec<_> =
+                    self.sockets.iter().map(|s| s.as_raw_fd()).collect();
+                let (process, address, mut bus_pid) = Process::spawn_dbus_daemon(
+                    program,
+                    &config_file,
+                    &sockets,
+                    &options,
+                )?;
+                if self.sandbox.is_some() {
+                    bus_pid = sandbox::host_pid(process.pid(), bus_pid)?;
+                }
                 Ok(Daemon {
                     address,
                     tmp_dir,
                     process,
+                    bus_pid,
+                    controller: None,
+                    conn: Mutex::new(None),
+                    monitors: Vec::new(),
+                    capture: None,
+                    leak_baseline: None,
+                    proxies: Vec::new(),
                 })
             }
             DaemonType::DBusBroker => {
-                let path = tmp_dir.path().join("socket");
-                let address = format!("unix:path={}", escape_path(&path));
-                let socket = UnixListener::bind(&path)?;
-                let process = Process::spawn_dbus_broker(
-                    program,
-                    &config_file,
-                    socket.as_raw_fd(),
-                )?;
-                Ok(Daemon {
-                    address,
+                let (sockets, listeners) = self.broker_sockets(&config, &tmp_dir)?;
+                let fds: Vec<_> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+                let process =
+                    Process::spawn_dbus_broker(program, &config_file, &fds, &options)?;
+                // Close our copies of sockets, so that connection attempts
+                // fail once the broker exits.
+                drop(listeners);
+                let mut daemon = Daemon {
+                    address: broker_address(&sockets),
                     tmp_dir,
+                    bus_pid: process.pid(),
                     process,
-                })
+                    controller: None,
+                    conn: Mutex::new(None),
+                    monitors: Vec::new(),
+                    capture: None,
+                    leak_baseline: None,
+                    proxies: Vec::new(),
+                };
+                // The configuration is parsed only after the exec, wait
+                // until the broker is ready to accept connections.
+                let bus_pid = daemon.process.wait_ready(STARTUP_TIMEOUT, move || {
+                    let stream = sockets[0].connect()?;
+                    // Don't keep the thread around if the broker hangs.
+                    stream.set_read_timeout(Some(STARTUP_TIMEOUT))?;
+                    stream.set_write_timeout(Some(STARTUP_TIMEOUT))?;
+                    let mut conn = Connection::from_stream(stream)?;
+                    conn.hello()?;
+                    conn.bus_pid()
+                })? as libc::pid_t;
+                daemon.bus_pid = match self.sandbox {
+                    Some(_) => sandbox::host_pid(daemon.process.pid(), bus_pid)?,
+                    None => bus_pid,
+                };
+                Ok(daemon)
+            }
+            DaemonType::DBusBrokerDirect => {
+                let mut services = Vec::new();
+                for dir in &config.service_dirs {
+                    services.extend(controller::read_service_dir(dir)?);
+                }
+                let (sockets, listeners) = self.broker_sockets(&config, &tmp_dir)?;
+                let (process, stream) =
+                    Process::spawn_dbus_broker_direct(program, &options)?;
+                let mut daemon = Daemon {
+                    address: broker_address(&sockets),
+                    tmp_dir,
+                    bus_pid: process.pid(),
+                    process,
+                    controller: None,
+                    conn: Mutex::new(None),
+                    monitors: Vec::new(),
+                    capture: None,
+                    leak_baseline: None,
+                    proxies: Vec::new(),
+                };
+                let env = controller::Environment {
+                    address: daemon.address.clone(),
+                    process_group: daemon.process.pid(),
+                    uid: self
+                        .credentials
+                        .uid
+                        .unwrap_or_else(|| unsafe { libc::getuid() }),
+                    bus_type: config.bus_type.map(|bus_type| match bus_type {
+                        BusType::Session => "session",
+                        BusType::System => "system",
+                    }),
+                };
+                let conn = Connection::from_stream(stream)?;
+                daemon.controller =
+                    Some(controller::start(conn, listeners, services, env)?);
+                Ok(daemon)
             }
         }
     }
 }
 
+fn broker_address(sockets: &[UnixAddress]) -> String {
+    let addresses: Vec<_> = sockets.iter().map(UnixAddress::address).collect();
+    addresses.join(";")
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn unsupported(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
 fn escape_path(path: &Path) -> String {
+    escape(path.as_os_str().as_bytes())
+}
+
+fn escape(bytes: &[u8]) -> String {
     use std::fmt::Write;
 
     let mut escaped = String::new();
-    for b in path.as_os_str().as_bytes().iter().cloned() {
+    for b in bytes.iter().cloned() {
         match b {
             b'-'
             | b'0'..=b'9'
@@ -285,7 +948,7 @@ fn escape_path(path: &Path) -> String {
                 escaped.push(b.into());
             }
             _ => {
-                write!(&mut escaped, "%{0:2x}", b).unwrap();
+                write!(&mut escaped, "%{:02x}", b).unwrap();
             }
         }
     }
@@ -306,18 +969,371 @@ impl Daemon {
         self.tmp_dir.path()
     }
 
+    /// Connects to the message bus.
+    ///
+    /// See [`client::Connection::open`] for details.
+    pub fn connect(&self) -> io::Result<client::Connection> {
+        client::Connection::open(&self.address)
+    }
+
+    /// Returns names currently owned on the bus.
+    ///
+    /// The connection used to query the bus is not included.
+    pub fn list_names(&self) -> io::Result<Vec<BusName>> {
+        self.with_connection(|conn| {
+            let mut names = conn.list_names()?;
+            let own = conn.unique_name();
+            names.retain(|name| name.as_str() != own);
+            Ok(names)
+        })
+    }
+
+    /// Returns names that can be started by activation.
+    pub fn list_activatable_names(&self) -> io::Result<Vec<BusName>> {
+        self.with_connection(|conn| conn.list_activatable_names())
+    }
+
+    /// Returns the unique name of the owner of given name, or `None` if the
+    /// name has no owner.
+    pub fn name_owner<N: AsRef<str>>(&self, name: N) -> io::Result<Option<BusName>> {
+        self.with_connection(|conn| conn.name_owner(name.as_ref()))
+    }
+
+    /// Returns credentials of the connection owning given name.
+    pub fn connection_credentials<N: AsRef<str>>(
+        &self,
+        name: N,
+    ) -> io::Result<client::ConnectionCredentials> {
+        self.with_connection(|conn| conn.connection_credentials(name.as_ref()))
+    }
+
+    /// Returns optional features supported by the message bus.
+    pub fn features(&self) -> io::Result<Vec<String>> {
+        self.with_connection(|conn| conn.features())
+    }
+
+    /// Returns statistics of the bus and its connections.
+    ///
+    /// Requires a dbus-daemon built with statistics support, and calls to
+    /// the statistics interface allowed with [`Launcher::allow_stats`]. The
+    /// connection used to query the bus is not included among connections,
+    /// but it is counted in the statistics of the whole bus.
+    pub fn stats(&self) -> io::Result<Stats> {
+        self.with_connection(|conn| {
+            let mut stats = stats::query(conn)?;
+            stats.retain_connections(|c| c.unique_name().as_str() != conn.unique_name());
+            Ok(stats)
+        })
+    }
+
+    /// Returns changes of name owners on the bus from now on, received on
+    /// a new connection.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// let daemon = dbus_launch::Launcher::daemon().launch().unwrap();
+    /// let mut events = daemon.name_events().unwrap();
+    /// // Start a service acquiring the name.
+    /// let owner = events
+    ///     .wait_for_owner("com.example.Test", Duration::from_secs(10))
+    ///     .unwrap();
+    /// ```
+    pub fn name_events(&self) -> io::Result<NameEvents> {
+        NameEvents::open(&self.address)
+    }
+
+    /// Requests the bus to start a service by activation, and waits at most
+    /// `timeout` until it acquires the name or fails to start.
+    ///
+    /// Fails with [`io::ErrorKind::TimedOut`] if there is no reply in time.
+    pub fn start_service<N: AsRef<str>>(
+        &self,
+        name: N,
+        timeout: Duration,
+    ) -> io::Result<client::StartReply> {
+        self.with_connection(|conn| {
+            conn.set_timeout(Some(timeout))?;
+            let reply = conn.start_service(name.as_ref());
+            conn.set_timeout(Some(QUERY_TIMEOUT))?;
+            reply
+        })
+    }
+
+    /// Runs given function with a connection to the bus, which is opened on
+    /// the first use and then reused.
+    fn with_connection<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut client::Connection) -> io::Result<T>,
+    {
+        let mut conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        if conn.is_none() {
+            let mut new = self.connect()?;
+            new.set_timeout(Some(QUERY_TIMEOUT))?;
+            *conn = Some(new);
+        }
+        f(conn.as_mut().unwrap())
+    }
+
     /// Returns the PID of the daemon process.
     pub fn pid(&self) -> libc::pid_t {
         self.process.pid()
     }
+
+    /// Returns processes started by the daemon that are still running,
+    /// typically activated services.
+    ///
+    /// The daemon runs in its own process group, which is inherited by
+    /// processes it starts. Processes that moved to a different process
+    /// group or session are not included. Available on Linux only.
+    pub fn services(&self) -> io::Result<Vec<ProcessInfo>> {
+        let pid = self.process.pid();
+        let members = process::group_members(pid)?;
+        let name = |pid| members.iter().find(|p| p.pid == pid).map(|p| &p.name);
+        // dbus-daemon forks a babysitter for each activated service, and the
+        // sandbox forks an init process for its PID namespace.
+        let helper = |p: &process::ProcessStat| {
+            (p.ppid == pid || p.ppid == self.bus_pid) && name(p.ppid) == Some(&p.name)
+        };
+        // Services added to the launcher are run through activation wrappers.
+        let activation_dir = self.tmp_dir.path().join(activation::DIR);
+        let wrapper = |p: &process::ProcessStat| {
+            process::cmdline(p.pid)
+                .map(|args| {
+                    args.iter()
+                        .any(|arg| activation::is_wrapper_arg(&activation_dir, arg))
+                })
+                .unwrap_or(false)
+        };
+        let services = members
+            .iter()
+            .filter(|p| p.pid != pid && p.pid != self.bus_pid && !helper(p))
+            .filter(|p| !wrapper(p))
+            .map(|p| ProcessInfo {
+                pid: p.pid,
+                name: p.name.clone(),
+            })
+            .collect();
+        Ok(services)
+    }
+
+    /// Returns activations of services added with
+    /// [`service`](Launcher::service), in order they started.
+    ///
+    /// Services found in other service directories are not tracked.
+    pub fn activations(&self) -> io::Result<Vec<Activation>> {
+        activation::read_log(&self.tmp_dir.path().join(activation::DIR))
+    }
+
+    /// Stops the daemon together with processes it started, and returns
+    /// those of them that were still running at the time, as reported by
+    /// [`services`](Daemon::services).
+    ///
+    /// Dropping the daemon stops it as well, but without the report.
+    ///
+    /// With [`Launcher::check_leaks`], the bus is checked for leftover
+    /// connections and match rules first, and an error is returned if any
+    /// are found.
+    pub fn stop(mut self) -> io::Result<Vec<ProcessInfo>> {
+        let leaks = self.check_leaks();
+        self.leak_baseline = None;
+        let services = self.services();
+        self.shutdown();
+        leaks?;
+        services
+    }
+
+    /// Returns connections and match rules on the bus, except those of the
+    /// daemon itself and of activatable services.
+    fn snapshot(&self) -> io::Result<leaks::Snapshot> {
+        self.with_connection(|conn| {
+            let mut ignored = vec![conn.unique_name().to_owned()];
+            ignored.extend(self.monitors.iter().map(|m| m.unique_name().to_owned()));
+            for name in conn.list_activatable_names()? {
+                if let Some(owner) = conn.name_owner(name.as_str())? {
+                    ignored.push(owner.into());
+                }
+            }
+            let ignored = |name: &BusName| ignored.iter().any(|n| n == name.as_str());
+
+            let mut connections = conn.list_names()?;
+            connections.retain(|name| name.is_unique() && !ignored(name));
+            let match_rules = match stats::query(conn) {
+                Ok(stats) => Some(
+                    stats
+                        .connections()
+                        .iter()
+                        .filter(|c| !ignored(c.unique_name()))
+                        .flat_map(|c| {
+                            let name = c.unique_name();
+                            c.match_rules()
+                                .iter()
+                                .map(move |r| (name.clone(), r.clone()))
+                        })
+                        .collect(),
+                ),
+                // Statistics are not supported.
+                Err(ref e) if client::MethodError::from_io(e).is_some() => None,
+                Err(e) => return Err(e),
+            };
+            Ok(leaks::Snapshot {
+                connections,
+                match_rules,
+            })
+        })
+    }
+
+    /// Compares connections and match rules with the baseline, if any, and
+    /// returns an error carrying [`Leaks`] if some were left over.
+    fn check_leaks(&self) -> io::Result<()> {
+        let baseline = match self.leak_baseline {
+            Some(ref baseline) => baseline,
+            None => return Ok(()),
+        };
+        let start = std::time::Instant::now();
+        loop {
+            match self.snapshot()?.leaks_since(baseline) {
+                None => return Ok(()),
+                Some(leaks) if start.elapsed() >= LEAK_TIMEOUT => {
+                    return Err(io::Error::other(leaks))
+                }
+                Some(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    /// Terminates all processes in the daemon process group, giving them
+    /// a chance to exit cleanly first.
+    fn shutdown(&mut self) {
+        let _ = self.process.kill_group(libc::SIGTERM);
+        let _ = self.process.try_wait_timeout(Duration::from_secs(10));
+        let _ = self.process.kill_group(libc::SIGKILL);
+        let _ = self.process.wait();
+        if let Some(controller) = self.controller.take() {
+            let _ = controller.join();
+        }
+        // Monitors exit once the bus closes their connections.
+        for monitor in &mut self.monitors {
+            monitor.join();
+        }
+        for proxy in &mut self.proxies {
+            proxy.stop();
+        }
+    }
+
+    /// Captures all messages on the bus from now on into a file in the
+    /// temporary directory of the daemon, and returns its path. See
+    /// [`Launcher::monitor`] for the file format.
+    ///
+    /// The directory is removed together with the file when the daemon is
+    /// dropped, unless the thread is panicking, e.g., because a test failed.
+    /// In that case the path is printed to standard error.
+    pub fn start_monitor(&mut self) -> io::Result<PathBuf> {
+        if let Some(ref path) = self.capture {
+            return Ok(path.clone());
+        }
+        let path = self.tmp_dir.path().join("monitor.pcap");
+        let monitor = monitor::Monitor::start(&self.address, &path)?;
+        self.monitors.push(monitor);
+        self.capture = Some(path.clone());
+        Ok(path)
+    }
+
+    /// Returns recent messages captured by the monitor in a text format
+    /// similar to that of dbus-monitor, or `None` if no monitor was started
+    /// with [`Launcher::monitor`] or [`start_monitor`](Daemon::start_monitor).
+    ///
+    /// The trace is printed to standard error automatically when the daemon
+    /// is dropped while the thread is panicking, e.g., because a test failed.
+    pub fn trace(&self) -> Option<String> {
+        self.monitors.first().map(|monitor| monitor.trace())
+    }
+
+    /// Starts a proxy forwarding connections to the bus while injecting
+    /// faults into the traffic, and returns the address of the proxy.
+    ///
+    /// The proxy listens on a socket in the temporary directory of the
+    /// daemon, and connects to the first unix address of the bus. It is
+    /// stopped together with the daemon. The bus sees proxied clients with
+    /// credentials of the current process, as described in [`FaultConfig`].
+    pub fn proxy(&mut self, faults: &FaultConfig) -> io::Result<String> {
+        let bus = self
+            .address
+            .split(';')
+            .filter_map(|address| Address::parse(address).ok())
+            .find(|address| address.transport == "unix")
+            .ok_or_else(|| unsupported("proxy requires a unix address of the bus"))?;
+        let bus = UnixAddress::from_address(&bus)?;
+        let path = self
+            .tmp_dir
+            .path()
+            .join(format!("proxy-{}", self.proxies.len()));
+        let proxy = proxy::Proxy::start(bus, UnixAddress::Path(path), faults)?;
+        let address = proxy.address();
+        self.proxies.push(proxy);
+        Ok(address)
+    }
+
+    /// Returns the PID of the process serving the bus connections.
+    ///
+    /// Unlike [`pid`](Daemon::pid), this is the dbus-broker process rather
+    /// than the dbus-broker-launch, and the daemon inside the sandbox rather
+    /// than the process waiting for it.
+    pub fn bus_pid(&self) -> libc::pid_t {
+        self.bus_pid
+    }
 }
 
 impl Drop for Daemon {
     fn drop(&mut self) {
-        let _ = self.process.kill(libc::SIGTERM);
-        let _ = self.process.try_wait_timeout(Duration::from_secs(10));
-        let _ = self.process.kill(libc::SIGKILL);
-        let _ = self.process.wait();
+        let leaks = match self.leak_baseline {
+            Some(_) if !std::thread::panicking() => self.check_leaks(),
+            _ => Ok(()),
+        };
+        self.shutdown();
+        if std::thread::panicking() {
+            if let Some(trace) = self.trace() {
+                eprintln!("D-Bus traffic before the panic:\n{}", trace);
+            }
+        }
+        if let Some(ref path) = self.capture {
+            if std::thread::panicking() {
+                self.tmp_dir.disable_cleanup(true);
+                eprintln!("D-Bus traffic captured in {}", path.display());
+            }
+        }
+        if let Err(ref e) = leaks {
+            if let Some(leaks) = Leaks::from_io(e) {
+                panic!("{}", leaks);
+            }
+        }
+    }
+}
+
+impl Resource {
+    fn to_raw(self) -> process::RlimitResource {
+        match self {
+            Resource::AddressSpace => libc::RLIMIT_AS,
+            Resource::Core => libc::RLIMIT_CORE,
+            Resource::Cpu => libc::RLIMIT_CPU,
+            Resource::Data => libc::RLIMIT_DATA,
+            Resource::FileSize => libc::RLIMIT_FSIZE,
+            Resource::NoFile => libc::RLIMIT_NOFILE,
+            Resource::Stack => libc::RLIMIT_STACK,
+        }
+    }
+}
+
+fn rlimit(soft: u64, hard: u64) -> libc::rlimit {
+    let raw = |limit: u64| match limit {
+        u64::MAX => libc::RLIM_INFINITY,
+        limit => limit as libc::rlim_t,
+    };
+    libc::rlimit {
+        rlim_cur: raw(soft),
+        rlim_max: raw(hard),
     }
 }
 
@@ -329,7 +1345,7 @@ impl Config {
 
         let mut s = String::new();
         s.push_str(DOCTYPE);
-        s.push_str("\n");
+        s.push('\n');
 
         let mut xml = XmlWriter::new(&mut s);
         xml.start_tag("busconfig");
@@ -344,6 +1360,15 @@ impl Config {
             );
         }
 
+        if let Some(ref user) = self.user {
+            xml.tag_with_text("user", user);
+        }
+
+        if self.keep_umask {
+            xml.start_tag("keep_umask");
+            xml.end_tag("keep_umask");
+        }
+
         if self.allow_anonymous {
             xml.start_tag("allow_anonymous");
             xml.end_tag("allow_anonymous");
@@ -372,6 +1397,13 @@ impl Config {
         xml.start_tag("policy");
         xml.attr("context", "default");
 
+        if self.user.is_some() {
+            // Otherwise only the daemon user would be allowed to connect.
+            xml.start_tag("allow");
+            xml.attr("user", "*");
+            xml.end_tag("allow");
+        }
+
         xml.start_tag("allow");
         xml.attr("receive_requested_reply", "true");
         xml.end_tag("allow");
@@ -384,6 +1416,13 @@ impl Config {
         xml.attr("own", "*");
         xml.end_tag("allow");
 
+        if self.allow_stats {
+            xml.start_tag("allow");
+            xml.attr("send_destination", "org.freedesktop.DBus");
+            xml.attr("send_interface", "org.freedesktop.DBus.Debug.Stats");
+            xml.end_tag("allow");
+        }
+
         xml.end_tag("policy");
 
         xml.end_tag("busconfig");
@@ -399,8 +1438,12 @@ mod tests {
     /// Verify xml config serialization.
     #[test]
     fn to_xml() {
-        let mut c = Config::default();
-        c.bus_type = Some(BusType::Session);
+        let mut c = Config {
+            bus_type: Some(BusType::Session),
+            user: Some("nobody".into()),
+            keep_umask: true,
+            ..Config::default()
+        };
         c.listen.push("unix:tmpdir=/tmp".into());
         c.auth.push(Auth::Anonymous);
         c.auth.push(Auth::External);
@@ -413,12 +1456,15 @@ mod tests {
  "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
 <busconfig>
   <type>session</type>
+  <user>nobody</user>
+  <keep_umask/>
   <listen>unix:tmpdir=/tmp</listen>
   <auth>ANONYMOUS</auth>
   <auth>EXTERNAL</auth>
   <auth>DBUS_COOKIE_SHA1</auth>
   <servicedir>/tmp/servicedir</servicedir>
   <policy context="default">
+    <allow user="*"/>
     <allow receive_requested_reply="true"/>
     <allow send_destination="*"/>
     <allow own="*"/>
@@ -429,9 +1475,22 @@ mod tests {
         assert_eq!(expected, actual, "\n\n{}.\n\n{}.", expected, actual);
     }
 
+    #[test]
+    fn to_xml_stats() {
+        let c = Config {
+            allow_stats: true,
+            ..Config::default()
+        };
+        let expected = r#"    <allow own="*"/>
+    <allow send_destination="org.freedesktop.DBus" send_interface="org.freedesktop.DBus.Debug.Stats"/>
+  </policy>"#;
+        assert!(c.to_xml().contains(expected), "{}", c.to_xml());
+    }
+
     #[test]
     fn escape() {
         assert_eq!("/", &escape_path(Path::new("/")));
         assert_eq!("/tmp/a%23b", &escape_path(Path::new("/tmp/a#b")));
+        assert_eq!("a%0ab", &super::escape(b"a\nb"));
     }
 }
@@ -0,0 +1,855 @@
+//! D-Bus message serialization.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::OwnedFd;
+
+/// Maximum length of a message, including header, header alignment padding
+/// and body.
+pub(crate) const MAX_MESSAGE_LEN: usize = 1 << 27;
+
+/// Maximum nesting depth of containers within a single complete type.
+const MAX_DEPTH: usize = 64;
+
+const HEADER_PATH: u8 = 1;
+const HEADER_INTERFACE: u8 = 2;
+const HEADER_MEMBER: u8 = 3;
+const HEADER_ERROR_NAME: u8 = 4;
+const HEADER_REPLY_SERIAL: u8 = 5;
+const HEADER_DESTINATION: u8 = 6;
+const HEADER_SENDER: u8 = 7;
+const HEADER_SIGNATURE: u8 = 8;
+const HEADER_UNIX_FDS: u8 = 9;
+
+/// Flag indicating that method call does not expect a reply.
+pub(crate) const FLAG_NO_REPLY_EXPECTED: u8 = 0x1;
+
+/// A type of a message.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum MessageType {
+    MethodCall,
+    MethodReturn,
+    Error,
+    Signal,
+}
+
+/// A D-Bus value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Boolean(bool),
+    Int16(i16),
+    Uint16(u16),
+    Int32(i32),
+    Uint32(u32),
+    Int64(i64),
+    Uint64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(String),
+    Signature(String),
+    /// An index into file descriptors attached to the message.
+    UnixFd(u32),
+    /// An array with given signature of elements.
+    Array(String, Vec<Value>),
+    Struct(Vec<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+    Variant(Box<Value>),
+}
+
+/// A D-Bus message.
+#[derive(Debug)]
+pub(crate) struct Message {
+    pub(crate) message_type: MessageType,
+    pub(crate) flags: u8,
+    pub(crate) serial: u32,
+    pub(crate) path: Option<String>,
+    pub(crate) interface: Option<String>,
+    pub(crate) member: Option<String>,
+    pub(crate) error_name: Option<String>,
+    pub(crate) reply_serial: Option<u32>,
+    pub(crate) destination: Option<String>,
+    pub(crate) sender: Option<String>,
+    pub(crate) body: Vec<Value>,
+    pub(crate) fds: Vec<OwnedFd>,
+}
+
+impl Value {
+    /// Returns a signature of the value.
+    pub fn signature(&self) -> String {
+        let mut s = String::new();
+        self.push_signature(&mut s);
+        s
+    }
+
+    fn push_signature(&self, s: &mut String) {
+        match self {
+            Value::Byte(_) => s.push('y'),
+            Value::Boolean(_) => s.push('b'),
+            Value::Int16(_) => s.push('n'),
+            Value::Uint16(_) => s.push('q'),
+            Value::Int32(_) => s.push('i'),
+            Value::Uint32(_) => s.push('u'),
+            Value::Int64(_) => s.push('x'),
+            Value::Uint64(_) => s.push('t'),
+            Value::Double(_) => s.push('d'),
+            Value::String(_) => s.push('s'),
+            Value::ObjectPath(_) => s.push('o'),
+            Value::Signature(_) => s.push('g'),
+            Value::UnixFd(_) => s.push('h'),
+            Value::Array(element, _) => {
+                s.push('a');
+                s.push_str(element);
+            }
+            Value::Struct(fields) => {
+                s.push('(');
+                for field in fields {
+                    field.push_signature(s);
+                }
+                s.push(')');
+            }
+            Value::DictEntry(key, value) => {
+                s.push('{');
+                key.push_signature(s);
+                value.push_signature(s);
+                s.push('}');
+            }
+            Value::Variant(_) => s.push('v'),
+        }
+    }
+
+    /// Checks that the value can be serialized: its signature is a single
+    /// complete type of at most 255 bytes and all array elements match the
+    /// declared element signature.
+    fn check(&self) -> Result<()> {
+        let sig = self.signature();
+        check_signature(&sig)?;
+        if complete_type_len(sig.as_bytes(), 0).ok() != Some(sig.len()) {
+            return Err(invalid_input(format!(
+                "signature {:?} is not a single complete type",
+                sig
+            )));
+        }
+        self.check_contents()
+    }
+
+    fn check_contents(&self) -> Result<()> {
+        match self {
+            Value::Signature(s) => {
+                check_signature(s)?;
+                split_signature(s)
+                    .map_err(|_| invalid_input(format!("invalid signature {:?}", s)))?;
+            }
+            Value::Array(element, values) => {
+                for value in values {
+                    let sig = value.signature();
+                    if sig != *element {
+                        return Err(invalid_input(format!(
+                            "array element {:?} does not match signature {:?}",
+                            sig, element
+                        )));
+                    }
+                    value.check_contents()?;
+                }
+            }
+            Value::Struct(fields) => {
+                for field in fields {
+                    field.check_contents()?;
+                }
+            }
+            Value::DictEntry(key, value) => {
+                key.check_contents()?;
+                value.check_contents()?;
+            }
+            Value::Variant(value) => value.check()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Returns a string if value is a string, object path or signature.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) | Value::ObjectPath(s) | Value::Signature(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl Message {
+    fn new(message_type: MessageType) -> Message {
+        Message {
+            message_type,
+            flags: 0,
+            serial: 0,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            body: Vec::new(),
+            fds: Vec::new(),
+        }
+    }
+
+    /// Returns a new method call message.
+    pub(crate) fn method_call(
+        destination: Option<&str>,
+        path: &str,
+        interface: &str,
+        member: &str,
+    ) -> Message {
+        let mut m = Message::new(MessageType::MethodCall);
+        m.destination = destination.map(str::to_owned);
+        m.path = Some(path.to_owned());
+        m.interface = Some(interface.to_owned());
+        m.member = Some(member.to_owned());
+        m
+    }
+
+    /// Returns a new error message replying to given method call.
+    pub(crate) fn error(call: &Message, name: &str, text: &str) -> Message {
+        let mut m = Message::new(MessageType::Error);
+        m.reply_serial = Some(call.serial);
+        m.destination = call.sender.clone();
+        m.error_name = Some(name.to_owned());
+        m.body.push(Value::String(text.to_owned()));
+        m
+    }
+
+    /// Appends an argument to the message body.
+    pub(crate) fn arg(mut self, value: Value) -> Message {
+        self.body.push(value);
+        self
+    }
+
+    /// Returns true if message is a method call or a signal with given
+    /// interface and member.
+    pub(crate) fn is(&self, interface: &str, member: &str) -> bool {
+        self.interface.as_deref() == Some(interface)
+            && self.member.as_deref() == Some(member)
+    }
+
+    /// Returns a signature of the message body.
+    pub(crate) fn signature(&self) -> String {
+        let mut s = String::new();
+        for value in &self.body {
+            value.push_signature(&mut s);
+        }
+        s
+    }
+
+    /// Serializes the message using little endian byte order.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the body cannot be
+    /// serialized.
+    pub(crate) fn marshal(&self) -> Result<Vec<u8>> {
+        for value in &self.body {
+            value.check()?;
+        }
+        check_signature(&self.signature())?;
+
+        let mut body = Writer::default();
+        for value in &self.body {
+            body.value(value);
+        }
+
+        let mut fields = Vec::new();
+        let mut field = |code: u8, value: Value| {
+            fields.push(Value::Struct(vec![
+                Value::Byte(code),
+                Value::Variant(Box::new(value)),
+            ]));
+        };
+        if let Some(ref path) = self.path {
+            field(HEADER_PATH, Value::ObjectPath(path.clone()));
+        }
+        if let Some(ref interface) = self.interface {
+            field(HEADER_INTERFACE, Value::String(interface.clone()));
+        }
+        if let Some(ref member) = self.member {
+            field(HEADER_MEMBER, Value::String(member.clone()));
+        }
+        if let Some(ref error_name) = self.error_name {
+            field(HEADER_ERROR_NAME, Value::String(error_name.clone()));
+        }
+        if let Some(reply_serial) = self.reply_serial {
+            field(HEADER_REPLY_SERIAL, Value::Uint32(reply_serial));
+        }
+        if let Some(ref destination) = self.destination {
+            field(HEADER_DESTINATION, Value::String(destination.clone()));
+        }
+        if let Some(ref sender) = self.sender {
+            field(HEADER_SENDER, Value::String(sender.clone()));
+        }
+        if !self.body.is_empty() {
+            field(HEADER_SIGNATURE, Value::Signature(self.signature()));
+        }
+        if !self.fds.is_empty() {
+            field(HEADER_UNIX_FDS, Value::Uint32(self.fds.len() as u32));
+        }
+
+        let mut w = Writer::default();
+        w.buf.push(b'l');
+        w.buf.push(match self.message_type {
+            MessageType::MethodCall => 1,
+            MessageType::MethodReturn => 2,
+            MessageType::Error => 3,
+            MessageType::Signal => 4,
+        });
+        w.buf.push(self.flags);
+        w.buf.push(1);
+        w.u32(body.buf.len() as u32);
+        w.u32(self.serial);
+        w.value(&Value::Array("(yv)".to_owned(), fields));
+        w.align(8);
+        w.buf.extend_from_slice(&body.buf);
+
+        if w.buf.len() > MAX_MESSAGE_LEN {
+            return Err(Error::new(ErrorKind::InvalidInput, "message too long"));
+        }
+        Ok(w.buf)
+    }
+
+    /// Returns the total length of a message given the first 16 bytes of it.
+    pub(crate) fn length(header: &[u8; 16]) -> Result<usize> {
+        let r = Reader::new(&header[..], header[0])?;
+        let body_len = r.u32_at(4) as usize;
+        let fields_len = r.u32_at(12) as usize;
+        let header_len = 16 + fields_len;
+        let len = header_len + padding(header_len, 8) + body_len;
+        if len > MAX_MESSAGE_LEN {
+            return Err(invalid("message too long"));
+        }
+        Ok(len)
+    }
+
+    /// Deserializes a complete message. File descriptors are taken from the
+    /// front of `fds` according to the number declared in the header.
+    pub(crate) fn unmarshal(buf: &[u8], fds: &mut Vec<OwnedFd>) -> Result<Message> {
+        let (mut m, unix_fds) = Message::parse(buf)?;
+        if fds.len() < unix_fds {
+            return Err(invalid("missing file descriptors"));
+        }
+        m.fds = fds.drain(..unix_fds).collect();
+        Ok(m)
+    }
+
+    /// Deserializes a complete message without attaching file descriptors
+    /// declared in the header, e.g., a message received by a monitor.
+    pub(crate) fn unmarshal_without_fds(buf: &[u8]) -> Result<Message> {
+        Ok(Message::parse(buf)?.0)
+    }
+
+    /// Deserializes a message, returning it together with the number of file
+    /// descriptors declared in the header.
+    fn parse(buf: &[u8]) -> Result<(Message, usize)> {
+        if buf.len() < 16 {
+            return Err(invalid("message truncated"));
+        }
+        let mut r = Reader::new(buf, buf[0])?;
+        let message_type = match buf[1] {
+            1 => MessageType::MethodCall,
+            2 => MessageType::MethodReturn,
+            3 => MessageType::Error,
+            4 => MessageType::Signal,
+            _ => return Err(invalid("unknown message type")),
+        };
+        if buf[3] != 1 {
+            return Err(invalid("unsupported protocol version"));
+        }
+        let mut m = Message::new(message_type);
+        m.flags = buf[2];
+        let body_len = r.u32_at(4) as usize;
+        m.serial = r.u32_at(8);
+        r.pos = 12;
+
+        let mut signature = String::new();
+        let mut unix_fds = 0;
+        let fields = match r.value(b"a(yv)", 0)? {
+            Value::Array(_, fields) => fields,
+            _ => unreachable!(),
+        };
+        for field in fields {
+            let mut field = match field {
+                Value::Struct(field) => field.into_iter(),
+                _ => unreachable!(),
+            };
+            let code = field.next();
+            let value = match field.next() {
+                Some(Value::Variant(value)) => *value,
+                _ => unreachable!(),
+            };
+            match (code, value) {
+                (Some(Value::Byte(HEADER_PATH)), Value::ObjectPath(s)) => {
+                    m.path = Some(s)
+                }
+                (Some(Value::Byte(HEADER_INTERFACE)), Value::String(s)) => {
+                    m.interface = Some(s)
+                }
+                (Some(Value::Byte(HEADER_MEMBER)), Value::String(s)) => {
+                    m.member = Some(s)
+                }
+                (Some(Value::Byte(HEADER_ERROR_NAME)), Value::String(s)) => {
+                    m.error_name = Some(s)
+                }
+                (Some(Value::Byte(HEADER_REPLY_SERIAL)), Value::Uint32(n)) => {
+                    m.reply_serial = Some(n)
+                }
+                (Some(Value::Byte(HEADER_DESTINATION)), Value::String(s)) => {
+                    m.destination = Some(s)
+                }
+                (Some(Value::Byte(HEADER_SENDER)), Value::String(s)) => {
+                    m.sender = Some(s)
+                }
+                (Some(Value::Byte(HEADER_SIGNATURE)), Value::Signature(s)) => {
+                    signature = s
+                }
+                (Some(Value::Byte(HEADER_UNIX_FDS)), Value::Uint32(n)) => {
+                    unix_fds = n as usize
+                }
+                (Some(Value::Byte(code)), _) if code <= HEADER_UNIX_FDS => {
+                    return Err(invalid("header field has unexpected type"));
+                }
+                // Unknown header fields must be ignored.
+                _ => {}
+            }
+        }
+
+        r.align(8)?;
+        if buf.len() - r.pos != body_len {
+            return Err(invalid("body length mismatch"));
+        }
+
+        // Body alignment is relative to its start which is 8-byte aligned.
+        let mut body = Reader::new(&buf[r.pos..], buf[0])?;
+        for t in split_signature(&signature)? {
+            m.body.push(body.value(t.as_bytes(), 0)?);
+        }
+        if body.pos != body.buf.len() {
+            return Err(invalid("trailing bytes in message body"));
+        }
+        Ok((m, unix_fds))
+    }
+}
+
+/// Splits signature into a list of single complete types.
+pub(crate) fn split_signature(signature: &str) -> Result<Vec<&str>> {
+    let mut types = Vec::new();
+    let mut rest = signature;
+    while !rest.is_empty() {
+        let n = complete_type_len(rest.as_bytes(), 0)?;
+        types.push(&rest[..n]);
+        rest = &rest[n..];
+    }
+    Ok(types)
+}
+
+/// Returns the length of a single complete type at the start of signature.
+fn complete_type_len(sig: &[u8], depth: usize) -> Result<usize> {
+    if depth > MAX_DEPTH {
+        return Err(invalid("signature nested too deeply"));
+    }
+    match sig.first() {
+        Some(b'y') | Some(b'b') | Some(b'n') | Some(b'q') | Some(b'i') | Some(b'u')
+        | Some(b'x') | Some(b't') | Some(b'd') | Some(b's') | Some(b'o')
+        | Some(b'g') | Some(b'h') | Some(b'v') => Ok(1),
+        Some(b'a') => Ok(1 + complete_type_len(&sig[1..], depth + 1)?),
+        Some(b'(') => {
+            let mut n = 1;
+            while sig.get(n) != Some(&b')') {
+                n += complete_type_len(&sig[n..], depth + 1)?;
+            }
+            if n == 1 {
+                return Err(invalid("empty struct in signature"));
+            }
+            Ok(n + 1)
+        }
+        Some(b'{') => {
+            let key = complete_type_len(&sig[1..], depth + 1)?;
+            let value = complete_type_len(&sig[1 + key..], depth + 1)?;
+            if key != 1 || sig[1] == b'v' || sig.get(1 + key + value) != Some(&b'}') {
+                return Err(invalid("invalid dict entry in signature"));
+            }
+            Ok(key + value + 2)
+        }
+        _ => Err(invalid("invalid signature")),
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// Checks that signature fits into its single byte length.
+fn check_signature(sig: &str) -> Result<()> {
+    if sig.len() > 255 {
+        return Err(invalid_input(format!(
+            "signature {:?} is longer than 255 bytes",
+            sig
+        )));
+    }
+    Ok(())
+}
+
+fn padding(pos: usize, alignment: usize) -> usize {
+    (alignment - pos % alignment) % alignment
+}
+
+fn alignment(sig: u8) -> usize {
+    match sig {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'b' | b'i' | b'u' | b's' | b'o' | b'h' | b'a' => 4,
+        _ => 8,
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, alignment: usize) {
+        let n = padding(self.buf.len(), alignment);
+        self.buf.resize(self.buf.len() + n, 0);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, s: &str) {
+        self.buf.push(s.len() as u8);
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn value(&mut self, value: &Value) {
+        match *value {
+            Value::Byte(v) => self.buf.push(v),
+            Value::Boolean(v) => self.u32(v.into()),
+            Value::Int16(v) => {
+                self.align(2);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Uint16(v) => {
+                self.align(2);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Int32(v) => {
+                self.align(4);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Uint32(v) | Value::UnixFd(v) => self.u32(v),
+            Value::Int64(v) => {
+                self.align(8);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Uint64(v) => {
+                self.align(8);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::Double(v) => {
+                self.align(8);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            Value::String(ref s) | Value::ObjectPath(ref s) => self.string(s),
+            Value::Signature(ref s) => self.signature(s),
+            Value::Array(ref element, ref values) => {
+                self.u32(0);
+                let len_pos = self.buf.len() - 4;
+                self.align(alignment(element.as_bytes()[0]));
+                let start = self.buf.len();
+                for v in values {
+                    self.value(v);
+                }
+                let len = (self.buf.len() - start) as u32;
+                self.buf[len_pos..len_pos + 4].copy_from_slice(&len.to_le_bytes());
+            }
+            Value::Struct(ref fields) => {
+                self.align(8);
+                for v in fields {
+                    self.value(v);
+                }
+            }
+            Value::DictEntry(ref key, ref value) => {
+                self.align(8);
+                self.value(key);
+                self.value(value);
+            }
+            Value::Variant(ref value) => {
+                self.signature(&value.signature());
+                self.value(value);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], endian: u8) -> Result<Reader<'a>> {
+        let big_endian = match endian {
+            b'l' => false,
+            b'B' => true,
+            _ => return Err(invalid("invalid endianness")),
+        };
+        Ok(Reader {
+            buf,
+            pos: 0,
+            big_endian,
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> u32 {
+        let b = self.buf[pos..pos + 4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    fn align(&mut self, alignment: usize) -> Result<()> {
+        let n = padding(self.pos, alignment);
+        let padding = self.take(n)?;
+        if padding.iter().any(|&b| b != 0) {
+            return Err(invalid("non-zero padding"));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(invalid("message truncated"));
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N]> {
+        self.align(N)?;
+        let mut b: [u8; N] = self.take(N)?.try_into().unwrap();
+        if self.big_endian {
+            b.reverse();
+        }
+        // Now in little endian byte order.
+        Ok(b)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.fixed()?))
+    }
+
+    fn string(&mut self, len: usize) -> Result<String> {
+        let b = self.take(len + 1)?;
+        if b[len] != 0 {
+            return Err(invalid("string is not nul terminated"));
+        }
+        match std::str::from_utf8(&b[..len]) {
+            Ok(s) if !s.contains('\0') => Ok(s.to_owned()),
+            _ => Err(invalid("string is not valid UTF-8")),
+        }
+    }
+
+    fn signature(&mut self) -> Result<String> {
+        let len = self.take(1)?[0] as usize;
+        let s = self.string(len)?;
+        split_signature(&s)?;
+        Ok(s)
+    }
+
+    fn value(&mut self, sig: &[u8], depth: usize) -> Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("value nested too deeply"));
+        }
+        Ok(match sig[0] {
+            b'y' => Value::Byte(self.take(1)?[0]),
+            b'b' => match self.u32()? {
+                0 => Value::Boolean(false),
+                1 => Value::Boolean(true),
+                _ => return Err(invalid("invalid boolean value")),
+            },
+            b'n' => Value::Int16(i16::from_le_bytes(self.fixed()?)),
+            b'q' => Value::Uint16(u16::from_le_bytes(self.fixed()?)),
+            b'i' => Value::Int32(i32::from_le_bytes(self.fixed()?)),
+            b'u' => Value::Uint32(self.u32()?),
+            b'h' => Value::UnixFd(self.u32()?),
+            b'x' => Value::Int64(i64::from_le_bytes(self.fixed()?)),
+            b't' => Value::Uint64(u64::from_le_bytes(self.fixed()?)),
+            b'd' => Value::Double(f64::from_le_bytes(self.fixed()?)),
+            b's' => {
+                let len = self.u32()? as usize;
+                Value::String(self.string(len)?)
+            }
+            b'o' => {
+                let len = self.u32()? as usize;
+                Value::ObjectPath(self.string(len)?)
+            }
+            b'g' => Value::Signature(self.signature()?),
+            b'v' => {
+                let sig = self.signature()?;
+                if split_signature(&sig)?.len() != 1 {
+                    return Err(invalid("variant signature is not a single type"));
+                }
+                Value::Variant(Box::new(self.value(sig.as_bytes(), depth + 1)?))
+            }
+            b'a' => {
+                let len = self.u32()? as usize;
+                let element = &sig[1..1 + complete_type_len(&sig[1..], depth)?];
+                self.align(alignment(element[0]))?;
+                if self.buf.len() - self.pos < len {
+                    return Err(invalid("message truncated"));
+                }
+                let end = self.pos + len;
+                let mut values = Vec::new();
+                while self.pos < end {
+                    values.push(self.value(element, depth + 1)?);
+                }
+                if self.pos != end {
+                    return Err(invalid("array length mismatch"));
+                }
+                let element = String::from_utf8(element.to_vec()).unwrap();
+                Value::Array(element, values)
+            }
+            b'(' => {
+                self.align(8)?;
+                let mut fields = Vec::new();
+                let mut i = 1;
+                while sig[i] != b')' {
+                    let n = complete_type_len(&sig[i..], depth)?;
+                    fields.push(self.value(&sig[i..i + n], depth + 1)?);
+                    i += n;
+                }
+                Value::Struct(fields)
+            }
+            b'{' => {
+                self.align(8)?;
+                let key = self.value(&sig[1..2], depth + 1)?;
+                let value = self.value(&sig[2..], depth + 1)?;
+                Value::DictEntry(Box::new(key), Box::new(value))
+            }
+            _ => return Err(invalid("invalid signature")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Verify that serialized message can be deserialized back.
+    #[test]
+    fn roundtrip() {
+        let mut m = Message::method_call(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+        )
+        .arg(Value::Byte(7))
+        .arg(Value::Struct(vec![
+            Value::Boolean(true),
+            Value::Uint64(u64::MAX),
+            Value::String("text".into()),
+        ]))
+        .arg(Value::Array(
+            "{sv}".into(),
+            vec![Value::DictEntry(
+                Box::new(Value::String("key".into())),
+                Box::new(Value::Variant(Box::new(Value::Int16(-2)))),
+            )],
+        ))
+        .arg(Value::Array("t".into(), vec![]))
+        .arg(Value::Double(0.5));
+        m.serial = 42;
+
+        let buf = m.marshal().unwrap();
+        let header: [u8; 16] = buf[..16].try_into().unwrap();
+        assert_eq!(buf.len(), Message::length(&header).unwrap());
+
+        let actual = Message::unmarshal(&buf, &mut Vec::new()).unwrap();
+        assert_eq!(m.message_type, actual.message_type);
+        assert_eq!(m.serial, actual.serial);
+        assert_eq!(m.destination, actual.destination);
+        assert_eq!(m.path, actual.path);
+        assert_eq!(m.interface, actual.interface);
+        assert_eq!(m.member, actual.member);
+        assert_eq!(m.body, actual.body);
+    }
+
+    #[test]
+    fn invalid_values() {
+        let marshal = |value: Value| {
+            Message::method_call(None, "/", "com.example.Test", "Test")
+                .arg(value)
+                .marshal()
+                .unwrap_err()
+                .kind()
+        };
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            marshal(Value::Array(String::new(), vec![]))
+        );
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            marshal(Value::Array("ss".into(), vec![]))
+        );
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            marshal(Value::Array("s".into(), vec![Value::Uint32(1)]))
+        );
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            marshal(Value::Variant(Box::new(Value::Array(
+                "u".into(),
+                vec![Value::Byte(1)]
+            ))))
+        );
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            marshal(Value::Signature("a".into()))
+        );
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            marshal(Value::Struct(vec![Value::Byte(0); 256]))
+        );
+
+        let mut m = Message::method_call(None, "/", "com.example.Test", "Test");
+        m.body = vec![Value::Byte(0); 256];
+        assert_eq!(ErrorKind::InvalidInput, m.marshal().unwrap_err().kind());
+    }
+
+    #[test]
+    fn signatures() {
+        assert_eq!(
+            vec!["a{sv}", "(ii)", "s"],
+            split_signature("a{sv}(ii)s").unwrap()
+        );
+        assert!(split_signature("a").is_err());
+        assert!(split_signature("()").is_err());
+        assert!(split_signature("{ss}").is_ok());
+        assert!(split_signature("a{vs}").is_err());
+        assert!(split_signature("(ss").is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Capturing bus traffic using a monitor connection.
+
+use crate::client::Connection;
+use crate::message::{Message, MessageType, Value, MAX_MESSAGE_LEN};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Result, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The pcap link type of D-Bus messages, LINKTYPE_DBUS.
+const LINKTYPE_DBUS: u32 = 231;
+
+/// Maximum number of messages kept in the text trace. Older messages are
+/// discarded first.
+const TRACE_LIMIT: usize = 1000;
+
+/// A monitor connection capturing bus traffic in a background thread.
+#[derive(Debug)]
+pub(crate) struct Monitor {
+    /// Unique name of the monitor connection.
+    unique_name: String,
+    thread: Option<JoinHandle<()>>,
+    /// Recent messages formatted as text.
+    trace: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Monitor {
+    /// Connects to the bus as a monitor and starts a thread writing received
+    /// messages to a pcap file at given path, and recording them in a text
+    /// trace. The thread exits once the connection is closed.
+    pub(crate) fn start(address: &str, path: &Path) -> Result<Monitor> {
+        let mut conn = Connection::open(address)?;
+        conn.become_monitor()?;
+        let unique_name = conn.unique_name().to_owned();
+        let mut file = File::create(path)?;
+        file.write_all(&header())?;
+        let mut file = Some(file);
+        let trace = Arc::new(Mutex::new(VecDeque::new()));
+        let thread_trace = trace.clone();
+        let thread = thread::Builder::new()
+            .name("dbus-monitor".to_owned())
+            .spawn(move || {
+                while let Ok(message) = conn.recv_raw() {
+                    let time = SystemTime::now();
+                    if let Some(ref mut f) = file {
+                        if f.write_all(&record(&message, time)).is_err() {
+                            file = None;
+                        }
+                    }
+                    let text = describe(&message, time);
+                    let mut trace =
+                        thread_trace.lock().unwrap_or_else(|e| e.into_inner());
+                    if trace.len() == TRACE_LIMIT {
+                        trace.pop_front();
+                    }
+                    trace.push_back(text);
+                }
+            })?;
+        Ok(Monitor {
+            unique_name,
+            thread: Some(thread),
+            trace,
+        })
+    }
+
+    /// Returns the unique name of the monitor connection.
+    pub(crate) fn unique_name(&self) -> &str {
+        &self.unique_name
+    }
+
+    /// Returns captured messages in the format of dbus-monitor.
+    pub(crate) fn trace(&self) -> String {
+        let trace = self.trace.lock().unwrap_or_else(|e| e.into_inner());
+        trace.iter().map(String::as_str).collect()
+    }
+
+    /// Waits for the monitor thread to exit.
+    pub(crate) fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns the pcap global header.
+fn header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    // Version 2.4.
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // Time zone offset and accuracy of timestamps.
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(MAX_MESSAGE_LEN as u32).to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_DBUS.to_le_bytes());
+    header
+}
+
+/// Returns a pcap record with a message received at given time.
+fn record(message: &[u8], time: SystemTime) -> Vec<u8> {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut record = Vec::with_capacity(16 + message.len());
+    record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    record.extend_from_slice(message);
+    record
+}
+
+/// Formats a serialized message in the style of dbus-monitor.
+fn describe(buf: &[u8], time: SystemTime) -> String {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let time = format!("{}.{:06}", time.as_secs(), time.subsec_micros());
+    let message = match Message::unmarshal_without_fds(buf) {
+        Ok(message) => message,
+        Err(e) => return format!("invalid message time={}: {}\n", time, e),
+    };
+
+    let mut s = String::new();
+    s.push_str(match message.message_type {
+        MessageType::MethodCall => "method call",
+        MessageType::MethodReturn => "method return",
+        MessageType::Error => "error",
+        MessageType::Signal => "signal",
+    });
+    let _ = write!(
+        s,
+        " time={} sender={} -> destination={} serial={}",
+        time,
+        message.sender.as_deref().unwrap_or("(null sender)"),
+        message
+            .destination
+            .as_deref()
+            .unwrap_or("(null destination)"),
+        message.serial
+    );
+    if let Some(ref name) = message.error_name {
+        let _ = write!(s, " error_name={}", name);
+    }
+    if let Some(reply_serial) = message.reply_serial {
+        let _ = write!(s, " reply_serial={}", reply_serial);
+    }
+    if let Some(ref path) = message.path {
+        let _ = write!(s, " path={};", path);
+    }
+    if let Some(ref interface) = message.interface {
+        let _ = write!(s, " interface={};", interface);
+    }
+    if let Some(ref member) = message.member {
+        let _ = write!(s, " member={}", member);
+    }
+    s.push('\n');
+    for value in &message.body {
+        describe_value(&mut s, value, 1);
+    }
+    s
+}
+
+fn describe_value(s: &mut String, value: &Value, depth: usize) {
+    for _ in 0..depth {
+        s.push_str("   ");
+    }
+    match value {
+        Value::Byte(v) => write!(s, "byte {}", v),
+        Value::Boolean(v) => write!(s, "boolean {}", v),
+        Value::Int16(v) => write!(s, "int16 {}", v),
+        Value::Uint16(v) => write!(s, "uint16 {}", v),
+        Value::Int32(v) => write!(s, "int32 {}", v),
+        Value::Uint32(v) => write!(s, "uint32 {}", v),
+        Value::Int64(v) => write!(s, "int64 {}", v),
+        Value::Uint64(v) => write!(s, "uint64 {}", v),
+        Value::Double(v) => write!(s, "double {}", v),
+        Value::String(v) => write!(s, "string {:?}", v),
+        Value::ObjectPath(v) => write!(s, "object path {:?}", v),
+        Value::Signature(v) => write!(s, "signature {:?}", v),
+        Value::UnixFd(v) => write!(s, "file descriptor {}", v),
+        Value::Array(_, values) => {
+            s.push_str("array [\n");
+            for value in values {
+                describe_value(s, value, depth + 1);
+            }
+            close(s, depth, "]")
+        }
+        Value::Struct(fields) => {
+            s.push_str("struct {\n");
+            for value in fields {
+                describe_value(s, value, depth + 1);
+            }
+            close(s, depth, "}")
+        }
+        Value::DictEntry(key, value) => {
+            s.push_str("dict entry(\n");
+            describe_value(s, key, depth + 1);
+            describe_value(s, value, depth + 1);
+            close(s, depth, ")")
+        }
+        Value::Variant(value) => {
+            s.push_str("variant ");
+            let mut inner = String::new();
+            describe_value(&mut inner, value, depth);
+            s.push_str(inner.trim_start());
+            return;
+        }
+    }
+    .unwrap();
+    s.push('\n');
+}
+
+fn close(s: &mut String, depth: usize, bracket: &str) -> std::fmt::Result {
+    for _ in 0..depth {
+        s.push_str("   ");
+    }
+    s.write_str(bracket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pcap() {
+        assert_eq!(
+            b"\xd4\xc3\xb2\xa1\x02\x00\x04\x00\0\0\0\0\0\0\0\0\0\0\0\x08\xe7\0\0\0",
+            &header()[..]
+        );
+        let time = UNIX_EPOCH + Duration::new(1, 2_000);
+        assert_eq!(
+            b"\x01\0\0\0\x02\0\0\0\x03\0\0\0\x03\0\0\0abc",
+            &record(b"abc", time)[..]
+        );
+    }
+
+    #[test]
+    fn text() {
+        let mut m = Message::method_call(
+            Some("org.freedesktop.DBus"),
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "RequestName",
+        )
+        .arg(Value::String("com.example.Test".into()))
+        .arg(Value::Array(
+            "{sv}".into(),
+            vec![Value::DictEntry(
+                Box::new(Value::String("key".into())),
+                Box::new(Value::Variant(Box::new(Value::Uint32(4)))),
+            )],
+        ));
+        m.serial = 3;
+        m.sender = Some(":1.7".into());
+        let time = UNIX_EPOCH + Duration::new(1, 2_000);
+        assert_eq!(
+            "method call time=1.000002 sender=:1.7 -> destination=org.freedesktop.DBus \
+             serial=3 path=/org/freedesktop/DBus; interface=org.freedesktop.DBus; \
+             member=RequestName\n   \
+             string \"com.example.Test\"\n   \
+             array [\n      \
+             dict entry(\n         \
+             string \"key\"\n         \
+             variant uint32 4\n      \
+             )\n   \
+             ]\n",
+            describe(&m.marshal().unwrap(), time)
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Validation of D-Bus names.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Maximum length of a name in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+/// A valid bus name, either unique or well-known.
+///
+/// # Examples
+///
+/// ```
+/// use dbus_launch::BusName;
+///
+/// let name = BusName::new("com.example.Test").unwrap();
+/// assert!(!name.is_unique());
+///
+/// let name = BusName::new(":1.42").unwrap();
+/// assert!(name.is_unique());
+///
+/// assert!(BusName::new("com/example").is_err());
+/// ```
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BusName(String);
+
+/// A valid interface name.
+///
+/// # Examples
+///
+/// ```
+/// use dbus_launch::InterfaceName;
+///
+/// assert!(InterfaceName::new("org.freedesktop.DBus").is_ok());
+/// assert!(InterfaceName::new("org.freedesktop-DBus").is_err());
+/// ```
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InterfaceName(String);
+
+impl BusName {
+    /// Returns a bus name if it is valid, and an error otherwise.
+    pub fn new(name: &str) -> io::Result<BusName> {
+        let result = match name.strip_prefix(':') {
+            Some(rest) => check_elements(rest, is_bus_name_byte, true),
+            None => check_elements(name, is_bus_name_byte, false),
+        };
+        match result {
+            Ok(()) if name.len() > MAX_NAME_LEN => {
+                Err(invalid_name("bus", name, "longer than 255 bytes"))
+            }
+            Ok(()) => Ok(BusName(name.to_owned())),
+            Err(reason) => Err(invalid_name("bus", name, reason)),
+        }
+    }
+
+    /// Returns true if this is a unique connection name, i.e., it starts with
+    /// a colon.
+    pub fn is_unique(&self) -> bool {
+        self.0.starts_with(':')
+    }
+
+    /// Returns the name as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl InterfaceName {
+    /// Returns an interface name if it is valid, and an error otherwise.
+    pub fn new(name: &str) -> io::Result<InterfaceName> {
+        let is_interface_byte = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+        match check_elements(name, is_interface_byte, false) {
+            Ok(()) if name.len() > MAX_NAME_LEN => {
+                Err(invalid_name("interface", name, "longer than 255 bytes"))
+            }
+            Ok(()) => Ok(InterfaceName(name.to_owned())),
+            Err(reason) => Err(invalid_name("interface", name, reason)),
+        }
+    }
+
+    /// Returns the name as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+macro_rules! impl_name_traits {
+    ($name:ident) => {
+        impl FromStr for $name {
+            type Err = io::Error;
+
+            fn from_str(s: &str) -> io::Result<$name> {
+                $name::new(s)
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = io::Error;
+
+            fn try_from(s: &str) -> io::Result<$name> {
+                $name::new(s)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = io::Error;
+
+            fn try_from(s: String) -> io::Result<$name> {
+                $name::new(&s)
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(name: $name) -> String {
+                name.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+    };
+}
+
+impl_name_traits!(BusName);
+impl_name_traits!(InterfaceName);
+
+fn is_bus_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'-'
+}
+
+fn invalid_name(kind: &str, name: &str, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid {} name {:?}: {}", kind, name, reason),
+    )
+}
+
+/// Checks that name consists of at least two non-empty elements separated
+/// by dots, where each element consists of allowed bytes and, unless
+/// `leading_digits` is true, does not start with a digit.
+fn check_elements(
+    name: &str,
+    allowed: impl Fn(u8) -> bool,
+    leading_digits: bool,
+) -> Result<(), &'static str> {
+    if name.is_empty() {
+        return Err("name is empty");
+    }
+    let mut elements = 0;
+    for element in name.split('.') {
+        elements += 1;
+        match element.as_bytes().first() {
+            None => return Err("name contains an empty element"),
+            Some(b) if b.is_ascii_digit() && !leading_digits => {
+                return Err("name element starts with a digit")
+            }
+            _ => {}
+        }
+        if !element.bytes().all(&allowed) {
+            return Err("name contains an invalid character");
+        }
+    }
+    if elements < 2 {
+        return Err("name has fewer than two elements");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bus_names() {
+        assert!(BusName::new("com.example.Test").is_ok());
+        assert!(BusName::new("com.example-1._x").is_ok());
+        assert!(BusName::new(":1.0").is_ok());
+        assert!(BusName::new(":1.0").unwrap().is_unique());
+        assert!(BusName::new("com").is_err());
+        assert!(BusName::new("").is_err());
+        assert!(BusName::new(":").is_err());
+        assert!(BusName::new(":1").is_err());
+        assert!(BusName::new("com..example").is_err());
+        assert!(BusName::new(".com.example").is_err());
+        assert!(BusName::new("com.1example").is_err());
+        assert!(BusName::new("com.example/Test").is_err());
+        assert!(BusName::new(&format!("a.{}", "b".repeat(253))).is_ok());
+        assert!(BusName::new(&format!("a.{}", "b".repeat(254))).is_err());
+    }
+
+    #[test]
+    fn interface_names() {
+        assert!(InterfaceName::new("org.freedesktop.DBus").is_ok());
+        assert!(InterfaceName::new("org.freedesktop.DBus.Debug.Stats").is_ok());
+        assert!(InterfaceName::new("org._1").is_ok());
+        assert!(InterfaceName::new("org").is_err());
+        assert!(InterfaceName::new("org.1x").is_err());
+        assert!(InterfaceName::new("org.free-desktop").is_err());
+        assert!(InterfaceName::new(":1.0").is_err());
+    }
+}
@@ -1,16 +1,100 @@
 use crate::pipe::Pipe;
-use crate::sys::{close_on_exec_from, execvpe, set_close_on_exec};
+use crate::sandbox::Prepared;
+use crate::sys::{close_on_exec_from, execvpe, random_bytes, set_close_on_exec};
 use std::ffi::{CString, OsStr};
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::os::raw::{c_char, c_int};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
 use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::process::ExitStatus;
 use std::ptr;
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Settings applied in the child process before exec.
+#[derive(Debug, Default)]
+pub(crate) struct Options {
+    /// Additional file descriptors passed to the child, as (source, target)
+    /// pairs.
+    pub(crate) fds: Vec<(c_int, c_int)>,
+    pub(crate) current_dir: Option<CString>,
+    pub(crate) umask: Option<libc::mode_t>,
+    pub(crate) rlimits: Vec<(RlimitResource, libc::rlimit)>,
+    pub(crate) credentials: Credentials,
+    pub(crate) sandbox: Option<Prepared>,
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub(crate) type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub(crate) type RlimitResource = c_int;
+
+/// User and groups to switch to before exec.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Credentials {
+    pub(crate) uid: Option<libc::uid_t>,
+    pub(crate) gid: Option<libc::gid_t>,
+    /// Supplementary groups. When unspecified, they are cleared if the uid or
+    /// gid changes and the process is privileged.
+    pub(crate) groups: Option<Vec<libc::gid_t>>,
+}
+
+impl Options {
+    /// Returns true if applying the options requires running code in the
+    /// child process.
+    #[cfg(target_os = "linux")]
+    fn requires_fork(&self) -> bool {
+        self.current_dir.is_some()
+            || self.umask.is_some()
+            || !self.rlimits.is_empty()
+            || !self.credentials.is_empty()
+            || self.sandbox.is_some()
+    }
+}
+
+impl Credentials {
+    /// Returns true if no changes are requested.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.groups.is_none()
+    }
+
+    /// Switches to the requested groups, then to the group and finally to the
+    /// user, since changing the user may drop the privileges necessary for
+    /// the former. Must be called in a child process after fork.
+    fn apply(&self) -> Result<()> {
+        let groups: &[libc::gid_t] = match self.groups {
+            Some(ref groups) => groups,
+            None if !self.is_empty() && unsafe { libc::geteuid() } == 0 => &[],
+            None => return self.apply_ids(),
+        };
+        if unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        self.apply_ids()
+    }
+
+    fn apply_ids(&self) -> Result<()> {
+        if let Some(gid) = self.gid {
+            if unsafe { libc::setgid(gid) } == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid) } == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How often to check whether a process exited while waiting for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 pub(crate) struct Process {
@@ -23,89 +107,110 @@ impl Process {
     pub(crate) fn spawn_dbus_daemon(
         program: Option<&OsStr>,
         config: &Path,
-    ) -> Result<(Self, String)> {
-        let (mut r, w) = Pipe::new()?;
+        sockets: &[c_int],
+        options: &Options,
+    ) -> Result<(Self, String, libc::pid_t)> {
+        let (mut address_r, address_w) = Pipe::new()?;
+        let (mut pid_r, pid_w) = Pipe::new()?;
 
+        // Sockets passed using socket activation come first.
+        let address_fd = 3 + sockets.len() as c_int;
+        let pid_fd = address_fd + 1;
         let mut argv = CStringArray::new();
         argv.push(program.unwrap_or(OsStr::new("dbus-daemon")));
         argv.push("--nofork");
         argv.push("--config-file");
         argv.push(config);
-        argv.push("--print-address=3");
-        let env = ptr::null();
-        let process = spawn(argv.as_ptr(), env, &mut || {
-            if w.as_raw_fd() != 3 && unsafe { libc::dup2(w.as_raw_fd(), 3) } == -1 {
-                return Err(Error::last_os_error());
-            }
-            set_close_on_exec(3, false)
-        })?;
+        argv.push(format!("--print-address={}", address_fd));
+        argv.push(format!("--print-pid={}", pid_fd));
+        let mut fds = vec![
+            (address_w.as_raw_fd(), address_fd),
+            (pid_w.as_raw_fd(), pid_fd),
+        ];
+        fds.extend_from_slice(&options.fds);
+        let mut process = if sockets.is_empty() {
+            spawn(argv.as_ptr(), ptr::null(), &fds, options, None)?
+        } else {
+            spawn_socket_activated(&argv, sockets, &fds, options)?
+        };
 
-        // Read the address from the pipe.
-        drop(w);
+        // Read the address and the pid from the pipes.
+        drop(address_w);
+        drop(pid_w);
         let mut address = String::new();
-        r.read_to_string(&mut address)?;
-        address = address.trim().to_string();
+        address_r.read_to_string(&mut address)?;
+        let mut pid = String::new();
+        pid_r.read_to_string(&mut pid)?;
 
-        if !address.is_empty() {
-            Ok((process, address))
-        } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "dbus-daemon returned empty address",
-            ))
+        match (address.trim(), pid.trim().parse()) {
+            ("", _) | (_, Err(_)) => {
+                match process.try_wait_timeout(Duration::from_secs(1))? {
+                    Some(status) => Err(exited_during_startup(status)),
+                    None => Err(Error::other("dbus-daemon returned empty address")),
+                }
+            }
+            (address, Ok(pid)) => Ok((process, address.to_owned(), pid)),
         }
     }
 
-    /// Spawns a new dbus-broker process using specified config file and listening socket.
+    /// Spawns a new dbus-broker process using specified config file and listening sockets.
     pub(crate) fn spawn_dbus_broker(
         program: Option<&OsStr>,
         config: &Path,
-        socket: c_int,
+        sockets: &[c_int],
+        options: &Options,
     ) -> Result<Self> {
         let mut argv = CStringArray::new();
         argv.push(program.unwrap_or(OsStr::new("dbus-broker-launch")));
         argv.push("--config-file");
         argv.push(config);
+        spawn_socket_activated(&argv, sockets, &options.fds, options)
+    }
 
-        let mut env = CStringArray::new();
-        for (mut var, val) in std::env::vars_os() {
-            if var == "LISTEN_PID" || var == "LISTEN_FDS" {
-                // Ignore. They have to be overwritten later anyway.
-                continue;
-            }
-            var.push("=");
-            var.push(val);
-            env.push(var);
-        }
-        env.push("LISTEN_FDS=1");
-        let mut listen_pid = [0u8; 30];
-        env.push_ptr(listen_pid.as_ptr().cast());
+    /// Spawns a new dbus-broker process controlled through the returned
+    /// end of a socket pair.
+    pub(crate) fn spawn_dbus_broker_direct(
+        program: Option<&OsStr>,
+        options: &Options,
+    ) -> Result<(Self, UnixStream)> {
+        let (controller, broker) = UnixStream::pair()?;
 
-        spawn(argv.as_ptr(), env.as_ptr(), &mut || {
-            if socket != 3 && unsafe { libc::dup2(socket, 3) } == -1 {
-                return Err(Error::last_os_error());
-            }
-            set_close_on_exec(3, false)?;
-            write!(&mut listen_pid[..], "LISTEN_PID={}\0", unsafe {
-                libc::getpid()
-            })
-        })
+        let mut argv = CStringArray::new();
+        argv.push(program.unwrap_or(OsStr::new("dbus-broker")));
+        argv.push("--controller=3");
+        argv.push("--machine-id");
+        argv.push(machine_id()?);
+        let env = ptr::null();
+        let mut fds = vec![(broker.as_raw_fd(), 3)];
+        fds.extend_from_slice(&options.fds);
+        let process = spawn(argv.as_ptr(), env, &fds, options, None)?;
+
+        Ok((process, controller))
     }
 
     pub(crate) fn pid(&self) -> libc::pid_t {
         self.pid
     }
 
-    pub(crate) fn kill(&mut self, signal: c_int) -> Result<()> {
+    /// Sends a signal to all processes in the process group of this process.
+    ///
+    /// Once this process has been reaped, the group ID may be reused by
+    /// unrelated processes after the group becomes empty, so the signal is
+    /// sent only if the group is known to have members left.
+    pub(crate) fn kill_group(&mut self, signal: c_int) -> Result<()> {
         if self.exit_status.is_some() {
-            return Ok(());
+            match group_members(self.pid) {
+                Ok(members) if !members.is_empty() => {}
+                _ => return Ok(()),
+            }
         }
-
-        if unsafe { libc::kill(self.pid, signal) } == -1 {
-            Err(Error::last_os_error())
-        } else {
-            Ok(())
+        if unsafe { libc::killpg(self.pid, signal) } == -1 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                return Err(err);
+            }
         }
+        Ok(())
     }
 
     pub(crate) fn wait(&mut self) -> Result<ExitStatus> {
@@ -149,41 +254,270 @@ impl Process {
             if let Some(status) = self.try_wait()? {
                 return Ok(Some(status));
             }
-            if let Some(left) = timeout.checked_sub(Duration::from_secs(1)) {
+            if let Some(left) = timeout.checked_sub(POLL_INTERVAL) {
                 timeout = left;
-                std::thread::sleep(Duration::from_secs(1));
+                std::thread::sleep(POLL_INTERVAL);
             } else {
                 std::thread::sleep(timeout);
                 return self.try_wait();
             }
         }
     }
+
+    /// Runs the readiness check on a separate thread and waits for it to
+    /// complete, failing early if the process exits in the meantime.
+    ///
+    /// The check is not interrupted when waiting fails, so it should limit
+    /// the duration of its blocking operations by itself.
+    pub(crate) fn wait_ready<T, F>(&mut self, timeout: Duration, check: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(check());
+        });
+
+        let start = Instant::now();
+        loop {
+            match rx.recv_timeout(Duration::from_millis(10)) {
+                Ok(Ok(value)) => return Ok(value),
+                Ok(Err(err)) => {
+                    // Prefer reporting the exit status, if the check failed
+                    // because the process exited.
+                    return match self.try_wait_timeout(Duration::from_secs(1))? {
+                        Some(status) => Err(exited_during_startup(status)),
+                        None => Err(err),
+                    };
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::other("readiness check panicked"));
+                }
+            }
+            if let Some(status) = self.try_wait()? {
+                return Err(exited_during_startup(status));
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "timed out waiting for daemon to become ready",
+                ));
+            }
+        }
+    }
+}
+
+fn exited_during_startup(status: ExitStatus) -> Error {
+    Error::other(format!("daemon exited during startup: {}", status))
+}
+
+/// Duplicates (source, target) file descriptor pairs, clearing close on
+/// exec flag of targets. Works correctly even when sources overlap with
+/// targets. The `tmp` must be of the same length as `fds` and is used to
+/// avoid allocation after fork.
+fn dup_all(fds: &[(c_int, c_int)], tmp: &mut [c_int]) -> Result<()> {
+    let end = first_free(fds);
+    // First move everything out of the way.
+    for (&(fd, _), tmp) in fds.iter().zip(tmp.iter_mut()) {
+        *tmp = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, end) };
+        if *tmp == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    for (&(_, target), &fd) in fds.iter().zip(tmp.iter()) {
+        if unsafe { libc::dup2(fd, target) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        set_close_on_exec(target, false)?;
+    }
+    Ok(())
+}
+
+/// Returns the lowest file descriptor, not less than 3, above all targets.
+fn first_free(fds: &[(c_int, c_int)]) -> c_int {
+    fds.iter()
+        .map(|&(_, target)| target + 1)
+        .fold(3, c_int::max)
+}
+
+/// A process as described in /proc/[pid]/stat.
+#[derive(Debug)]
+pub(crate) struct ProcessStat {
+    pub(crate) pid: libc::pid_t,
+    pub(crate) ppid: libc::pid_t,
+    pub(crate) name: String,
+}
+
+/// Returns processes in given process group.
+#[cfg(target_os = "linux")]
+pub(crate) fn group_members(pgid: libc::pid_t) -> Result<Vec<ProcessStat>> {
+    let mut members = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // The process may exit in the meantime.
+        let stat = match std::fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // The name is in parentheses and may contain anything, followed by
+        // state, ppid and pgrp.
+        let (name, rest) = match (stat.find('('), stat.rfind(')')) {
+            (Some(start), Some(end)) if start < end => {
+                (&stat[start + 1..end], &stat[end + 1..])
+            }
+            _ => continue,
+        };
+        let mut fields = rest.split_whitespace().skip(1);
+        let ppid = fields.next().and_then(|s| s.parse().ok());
+        let pgrp = fields.next().and_then(|s| s.parse::<libc::pid_t>().ok());
+        if let (Some(ppid), Some(pgrp)) = (ppid, pgrp) {
+            if pgrp == pgid {
+                members.push(ProcessStat {
+                    pid,
+                    ppid,
+                    name: name.to_owned(),
+                });
+            }
+        }
+    }
+    Ok(members)
+}
+
+/// Returns command line arguments of a process.
+pub(crate) fn cmdline(pid: libc::pid_t) -> Result<Vec<Vec<u8>>> {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid))?;
+    Ok(cmdline
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| arg.to_vec())
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn group_members(_pgid: libc::pid_t) -> Result<Vec<ProcessStat>> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "listing processes is supported on Linux only",
+    ))
+}
+
+/// Returns the machine ID of the host, or a random one if it is unavailable.
+fn machine_id() -> Result<String> {
+    for path in &["/etc/machine-id", "/var/lib/dbus/machine-id"] {
+        if let Ok(id) = std::fs::read_to_string(path) {
+            let id = id.trim();
+            if id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Ok(id.to_owned());
+            }
+        }
+    }
+    let mut random = [0u8; 16];
+    random_bytes(&mut random)?;
+    Ok(random.iter().map(|b| format!("{:02x}", b)).collect())
 }
 
+/// Spawns a new process with listening sockets passed using systemd socket
+/// activation protocol, i.e., as file descriptors starting from 3 described
+/// by LISTEN_FDS and LISTEN_PID environment variables. The `fds` are passed
+/// as well.
+///
+/// LISTEN_PID must be set in the child itself, since its PID is unknown
+/// beforehand, so such launches always fork rather than use posix_spawn.
+fn spawn_socket_activated(
+    argv: &CStringArray,
+    sockets: &[c_int],
+    fds: &[(c_int, c_int)],
+    options: &Options,
+) -> Result<Process> {
+    let mut env = CStringArray::new();
+    for (mut var, val) in std::env::vars_os() {
+        if var == "LISTEN_PID" || var == "LISTEN_FDS" {
+            // Ignore. They have to be overwritten later anyway.
+            continue;
+        }
+        var.push("=");
+        var.push(val);
+        env.push(var);
+    }
+    env.push(format!("LISTEN_FDS={}", sockets.len()));
+    let mut listen_pid = [0u8; 30];
+    env.push_ptr(listen_pid.as_ptr().cast());
+
+    // LISTEN_PID is known only after fork.
+    let mut pre_exec = || {
+        write!(&mut listen_pid[..], "LISTEN_PID={}\0", unsafe {
+            libc::getpid()
+        })
+    };
+    let mut all_fds: Vec<_> = sockets.iter().copied().zip(3..).collect();
+    all_fds.extend_from_slice(fds);
+    spawn(
+        argv.as_ptr(),
+        env.as_ptr(),
+        &all_fds,
+        options,
+        Some(&mut pre_exec),
+    )
+}
+
+/// Spawns a new process with `fds` duplicated from sources onto targets.
+/// All other file descriptors except for stdio are closed.
+///
+/// Uses posix_spawn when possible, i.e., when neither `pre_exec` nor any of
+/// the options require running code in the child, and fork otherwise. This
+/// excludes dbus-broker-launch and dbus-daemon with pre-bound sockets, see
+/// [`spawn_socket_activated`].
 fn spawn(
     argv: *const *const c_char,
     env: *const *const c_char,
-    pre_exec: &mut dyn FnMut() -> Result<()>,
+    fds: &[(c_int, c_int)],
+    options: &Options,
+    pre_exec: Option<&mut dyn FnMut() -> Result<()>>,
+) -> Result<Process> {
+    #[cfg(target_os = "linux")]
+    {
+        if pre_exec.is_none() && !options.requires_fork() {
+            if let Some(addclosefrom) = posix_spawn_addclosefrom() {
+                return posix_spawn(argv, env, fds, addclosefrom);
+            }
+        }
+    }
+    fork_exec(argv, env, fds, options, pre_exec)
+}
+
+fn fork_exec(
+    argv: *const *const c_char,
+    env: *const *const c_char,
+    fds: &[(c_int, c_int)],
+    options: &Options,
+    pre_exec: Option<&mut dyn FnMut() -> Result<()>>,
 ) -> Result<Process> {
     let (mut r, mut w) = Pipe::new()?;
 
-    if w.as_raw_fd() <= 3 {
-        // Avoid conflict with listen fd / print-address fd.
-        let fd =
-            unsafe { libc::fcntl(w.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 4 as c_int) };
+    let min = first_free(fds);
+    if w.as_raw_fd() < min {
+        // Avoid conflict with fds passed to the child.
+        let fd = unsafe { libc::fcntl(w.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min) };
         if fd == -1 {
             return Err(Error::last_os_error());
         }
-        assert!(fd > 3);
+        assert!(fd >= min);
         w = unsafe { Pipe::from_raw_fd(fd) };
     }
+    let mut tmp = vec![-1; fds.len()];
 
     let pid = unsafe { libc::fork() };
     if pid == -1 {
         Err(Error::last_os_error())
     } else if pid == 0 {
         // Child process
-        let error = try_exec(argv, env, pre_exec);
+        let error = try_exec(argv, env, fds, &mut tmp, options, pre_exec);
         let error = error.raw_os_error().unwrap_or(libc::EINVAL) as u32;
         let error = error.to_ne_bytes();
         let _ = w.write_all(&error);
@@ -208,31 +542,227 @@ fn spawn(
     }
 }
 
+#[cfg(target_os = "linux")]
+type AddCloseFrom =
+    unsafe extern "C" fn(*mut libc::posix_spawn_file_actions_t, c_int) -> c_int;
+
+/// Returns posix_spawn_file_actions_addclosefrom_np if provided by libc.
+///
+/// Looked up at runtime, since it is available only since glibc 2.34.
+#[cfg(target_os = "linux")]
+fn posix_spawn_addclosefrom() -> Option<AddCloseFrom> {
+    use std::sync::OnceLock;
+    static ADDCLOSEFROM: OnceLock<Option<AddCloseFrom>> = OnceLock::new();
+    *ADDCLOSEFROM.get_or_init(|| {
+        // RTLD_DEFAULT
+        let sym = unsafe {
+            libc::dlsym(
+                ptr::null_mut(),
+                b"posix_spawn_file_actions_addclosefrom_np\0"
+                    .as_ptr()
+                    .cast(),
+            )
+        };
+        if sym.is_null() {
+            None
+        } else {
+            Some(unsafe { std::mem::transmute::<*mut libc::c_void, AddCloseFrom>(sym) })
+        }
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn posix_spawn(
+    argv: *const *const c_char,
+    env: *const *const c_char,
+    fds: &[(c_int, c_int)],
+    addclosefrom: AddCloseFrom,
+) -> Result<Process> {
+    use std::mem::MaybeUninit;
+    use std::os::unix::io::OwnedFd;
+
+    fn check(ret: c_int) -> Result<()> {
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::from_raw_os_error(ret))
+        }
+    }
+
+    let mut inherited_env = CStringArray::new();
+    let env = if env.is_null() {
+        for (mut var, val) in std::env::vars_os() {
+            var.push("=");
+            var.push(val);
+            inherited_env.push(var);
+        }
+        inherited_env.as_ptr()
+    } else {
+        env
+    };
+
+    // Move sources out of the way of targets, so that duplication order
+    // doesn't matter.
+    let first_free = first_free(fds);
+    let mut moved = Vec::new();
+    let mut sources = Vec::with_capacity(fds.len());
+    for &(fd, _) in fds {
+        if fd >= first_free {
+            sources.push(fd);
+            continue;
+        }
+        let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, first_free) };
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        moved.push(unsafe { OwnedFd::from_raw_fd(fd) });
+        sources.push(fd);
+    }
+
+    let mut actions = MaybeUninit::uninit();
+    check(unsafe { libc::posix_spawn_file_actions_init(actions.as_mut_ptr()) })?;
+    let mut actions = FileActions(unsafe { actions.assume_init() });
+    let mut attr = MaybeUninit::uninit();
+    check(unsafe { libc::posix_spawnattr_init(attr.as_mut_ptr()) })?;
+    let mut attr = SpawnAttr(unsafe { attr.assume_init() });
+
+    for (&fd, &(_, target)) in sources.iter().zip(fds) {
+        check(unsafe {
+            libc::posix_spawn_file_actions_adddup2(&mut actions.0, fd, target)
+        })?;
+    }
+    // Close gaps between targets. Closing a file descriptor that is not open
+    // is not an error.
+    for fd in 3..first_free {
+        if !fds.iter().any(|&(_, target)| target == fd) {
+            check(unsafe {
+                libc::posix_spawn_file_actions_addclose(&mut actions.0, fd)
+            })?;
+        }
+    }
+    check(unsafe { addclosefrom(&mut actions.0, first_free) })?;
+
+    let mut signals = MaybeUninit::uninit();
+    unsafe { libc::sigemptyset(signals.as_mut_ptr()) };
+    let mut signals = unsafe { signals.assume_init() };
+    for &s in RESET_SIGNALS {
+        unsafe { libc::sigaddset(&mut signals, s) };
+    }
+    check(unsafe { libc::posix_spawnattr_setsigdefault(&mut attr.0, &signals) })?;
+    check(unsafe { libc::posix_spawnattr_setpgroup(&mut attr.0, 0) })?;
+    check(unsafe {
+        libc::posix_spawnattr_setflags(
+            &mut attr.0,
+            (libc::POSIX_SPAWN_SETSIGDEF | libc::POSIX_SPAWN_SETPGROUP) as _,
+        )
+    })?;
+
+    let mut pid = 0;
+    check(unsafe {
+        libc::posix_spawnp(
+            &mut pid,
+            *argv,
+            &actions.0,
+            &attr.0,
+            argv as *const *mut c_char,
+            env as *const *mut c_char,
+        )
+    })?;
+    Ok(Process {
+        pid,
+        exit_status: None,
+    })
+}
+
+#[cfg(target_os = "linux")]
+struct FileActions(libc::posix_spawn_file_actions_t);
+
+#[cfg(target_os = "linux")]
+impl Drop for FileActions {
+    fn drop(&mut self) {
+        unsafe { libc::posix_spawn_file_actions_destroy(&mut self.0) };
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct SpawnAttr(libc::posix_spawnattr_t);
+
+#[cfg(target_os = "linux")]
+impl Drop for SpawnAttr {
+    fn drop(&mut self) {
+        unsafe { libc::posix_spawnattr_destroy(&mut self.0) };
+    }
+}
+
+/// Signals whose disposition is reset to default in the child.
+const RESET_SIGNALS: &[c_int] = &[
+    libc::SIGCHLD,
+    libc::SIGINT,
+    libc::SIGTERM,
+    libc::SIGHUP,
+    libc::SIGPIPE,
+];
+
 fn try_exec(
     argv: *const *const c_char,
     env: *const *const c_char,
-    pre_exec: &mut dyn FnMut() -> Result<()>,
+    fds: &[(c_int, c_int)],
+    tmp: &mut [c_int],
+    options: &Options,
+    pre_exec: Option<&mut dyn FnMut() -> Result<()>>,
 ) -> Error {
-    for &s in &[
-        libc::SIGCHLD,
-        libc::SIGINT,
-        libc::SIGTERM,
-        libc::SIGHUP,
-        libc::SIGPIPE,
-    ] {
+    for &s in RESET_SIGNALS {
         if unsafe { libc::signal(s, libc::SIG_DFL) } == libc::SIG_ERR {
             return Error::last_os_error();
         }
     }
 
+    // Start a new process group, so that the daemon and services it
+    // activates can be terminated together.
+    if unsafe { libc::setpgid(0, 0) } == -1 {
+        return Error::last_os_error();
+    }
+
     if let Err(err) = close_on_exec_from(3) {
         return err;
     }
 
-    if let Err(err) = pre_exec() {
+    if let Some(mask) = options.umask {
+        unsafe { libc::umask(mask) };
+    }
+
+    for (resource, limit) in &options.rlimits {
+        if unsafe { libc::setrlimit(*resource, limit) } == -1 {
+            return Error::last_os_error();
+        }
+    }
+
+    if let Err(err) = options.credentials.apply() {
+        return err;
+    }
+
+    if let Some(ref sandbox) = options.sandbox {
+        if let Err(err) = sandbox.enter() {
+            return err;
+        }
+    }
+
+    if let Some(ref dir) = options.current_dir {
+        if unsafe { libc::chdir(dir.as_ptr()) } == -1 {
+            return Error::last_os_error();
+        }
+    }
+
+    if let Err(err) = dup_all(fds, tmp) {
         return err;
     }
 
+    if let Some(pre_exec) = pre_exec {
+        if let Err(err) = pre_exec() {
+            return err;
+        }
+    }
+
     if env.is_null() {
         unsafe { libc::execvp(*argv, argv) };
     } else {
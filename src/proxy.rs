@@ -0,0 +1,763 @@
+//! A proxy between clients and the bus injecting faults into the traffic.
+//!
+//! Each direction of a proxied connection is forwarded by its own thread.
+//! Faults are chosen by pseudo-random generators seeded from the seed of the
+//! configuration, the number of the connection and the direction. They are
+//! drawn at fixed positions in the forwarded data, at message boundaries and
+//! at the ends of chunks, rather than for each read from the socket. Hence
+//! they are reproducible as long as clients connect in the same order and
+//! send the same data, regardless of how the kernel splits it.
+//!
+//! File descriptors are passed along with the first byte of data they were
+//! received with.
+
+use crate::address::UnixAddress;
+use crate::message::Message;
+use crate::sys;
+use std::io::Result;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Maximum length of an authentication line, after which the stream is no
+/// longer interpreted.
+const MAX_LINE_LEN: usize = 16 * 1024;
+
+/// Faults injected by a proxy started with [`Daemon::proxy`].
+///
+/// By default the traffic is forwarded unchanged.
+///
+/// The bus takes credentials of its peers from the connected socket, so it
+/// sees the process of the proxy, i.e., the launching process, rather than
+/// the client. Policies and queries based on the process ID, user or
+/// security label of proxied clients reflect the proxy.
+///
+/// # Examples
+///
+/// ```no_run
+/// use dbus_launch::FaultConfig;
+/// use std::time::Duration;
+///
+/// let mut daemon = dbus_launch::Launcher::daemon().launch().unwrap();
+/// let address = daemon
+///     .proxy(
+///         FaultConfig::new(1)
+///             .chunk_size(3)
+///             .latency(Duration::ZERO, Duration::from_millis(1)),
+///     )
+///     .unwrap();
+/// // Connect the client under test to the address.
+/// ```
+///
+/// [`Daemon::proxy`]: crate::Daemon::proxy
+#[derive(Clone, Debug)]
+pub struct FaultConfig {
+    seed: u64,
+    latency: Option<(Duration, Duration)>,
+    chunk_size: Option<usize>,
+    drop_after_bytes: Option<u64>,
+    drop_after_messages: Option<u64>,
+    corrupt_message: Option<u64>,
+}
+
+impl FaultConfig {
+    /// Returns a configuration without faults, which are chosen using given
+    /// seed once enabled.
+    pub fn new(seed: u64) -> FaultConfig {
+        FaultConfig {
+            seed,
+            latency: None,
+            chunk_size: None,
+            drop_after_bytes: None,
+            drop_after_messages: None,
+            corrupt_message: None,
+        }
+    }
+
+    /// Delays each message by a random duration between `min` and `max`.
+    pub fn latency(&mut self, min: Duration, max: Duration) -> &mut Self {
+        self.latency = Some((min, max.max(min)));
+        self
+    }
+
+    /// Splits the forwarded data into chunks of random size between one and
+    /// `max` bytes, each written separately.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn chunk_size(&mut self, max: usize) -> &mut Self {
+        assert!(max > 0, "chunk size must be positive");
+        self.chunk_size = Some(max);
+        self
+    }
+
+    /// Closes the connection once `n` bytes were forwarded in either
+    /// direction.
+    pub fn drop_after_bytes(&mut self, n: u64) -> &mut Self {
+        self.drop_after_bytes = Some(n);
+        self
+    }
+
+    /// Closes the connection once `n` messages were forwarded in either
+    /// direction. The authentication preceding messages is not counted.
+    pub fn drop_after_messages(&mut self, n: u64) -> &mut Self {
+        self.drop_after_messages = Some(n);
+        self
+    }
+
+    /// Corrupts a random byte of a message sent from the bus to the client,
+    /// given by its index counting from zero.
+    ///
+    /// The fixed part of the header, which determines the message length,
+    /// is left intact, so that the following messages are not affected.
+    pub fn corrupt_message(&mut self, index: u64) -> &mut Self {
+        self.corrupt_message = Some(index);
+        self
+    }
+}
+
+/// A proxy listening for clients in a background thread.
+#[derive(Debug)]
+pub(crate) struct Proxy {
+    address: UnixAddress,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Proxy {
+    /// Starts listening at given address, forwarding connections to the bus.
+    pub(crate) fn start(
+        bus: UnixAddress,
+        address: UnixAddress,
+        faults: &FaultConfig,
+    ) -> Result<Proxy> {
+        let listener = address.bind()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let faults = faults.clone();
+        let thread = thread::Builder::new()
+            .name("dbus-proxy".to_owned())
+            .spawn(move || serve(listener, bus, faults, thread_stop))?;
+        Ok(Proxy {
+            address,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the address clients should connect to.
+    pub(crate) fn address(&self) -> String {
+        self.address.address()
+    }
+
+    /// Stops accepting connections, closes those already accepted and waits
+    /// for all threads to exit.
+    pub(crate) fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            // Wake up the thread waiting for connections.
+            let _ = self.address.connect();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Accepts connections until stopped.
+fn serve(
+    listener: UnixListener,
+    bus: UnixAddress,
+    faults: FaultConfig,
+    stop: Arc<AtomicBool>,
+) {
+    let mut connections = Vec::new();
+    let mut index = 0;
+    for client in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Ok(client) = client {
+            if let Ok(connection) = Connection::start(client, &bus, &faults, index) {
+                connections.push(connection);
+            }
+            index += 1;
+        }
+    }
+    for connection in connections {
+        connection.close();
+    }
+}
+
+/// A proxied connection.
+struct Connection {
+    client: UnixStream,
+    upstream: UnixStream,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Connection {
+    /// Connects to the bus on behalf of a client and starts forwarding.
+    fn start(
+        client: UnixStream,
+        bus: &UnixAddress,
+        faults: &FaultConfig,
+        index: u64,
+    ) -> Result<Connection> {
+        let upstream = bus.connect()?;
+        let pipes = vec![
+            (Direction::ToBus, client.try_clone()?, upstream.try_clone()?),
+            (
+                Direction::ToClient,
+                upstream.try_clone()?,
+                client.try_clone()?,
+            ),
+        ];
+        let mut threads = Vec::new();
+        for (direction, from, to) in pipes {
+            let mut pipe = Pipe::new(from, to, faults, index, direction);
+            let thread = thread::Builder::new()
+                .name("dbus-proxy".to_owned())
+                .spawn(move || pipe.run());
+            match thread {
+                Ok(thread) => threads.push(thread),
+                Err(e) => {
+                    Connection {
+                        client,
+                        upstream,
+                        threads,
+                    }
+                    .close();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(Connection {
+            client,
+            upstream,
+            threads,
+        })
+    }
+
+    fn close(self) {
+        let _ = self.client.shutdown(Shutdown::Both);
+        let _ = self.upstream.shutdown(Shutdown::Both);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    ToBus,
+    ToClient,
+}
+
+/// Forwards one direction of a connection.
+struct Pipe {
+    from: UnixStream,
+    to: UnixStream,
+    faults: FaultConfig,
+    direction: Direction,
+    rng: Rng,
+    framer: Framer,
+    chunker: Chunker,
+    /// Number of bytes forwarded so far.
+    bytes: u64,
+}
+
+impl Pipe {
+    fn new(
+        from: UnixStream,
+        to: UnixStream,
+        faults: &FaultConfig,
+        index: u64,
+        direction: Direction,
+    ) -> Pipe {
+        let stream = index * 4 + (direction == Direction::ToClient) as u64 * 2;
+        Pipe {
+            from,
+            to,
+            faults: faults.clone(),
+            direction,
+            rng: Rng::new(faults.seed, stream),
+            framer: Framer::new(direction),
+            chunker: Chunker::new(faults, Rng::new(faults.seed, stream + 1)),
+            bytes: 0,
+        }
+    }
+
+    fn run(&mut self) {
+        let _ = self.forward();
+        // One side closed the connection or a fault was injected, so close
+        // the other one too, which also stops the opposite direction.
+        let _ = self.from.shutdown(Shutdown::Both);
+        let _ = self.to.shutdown(Shutdown::Both);
+    }
+
+    fn forward(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut starts = Vec::new();
+        loop {
+            let mut fds = Vec::new();
+            let n = sys::recv_with_fds(self.from.as_raw_fd(), &mut buf, &mut fds)?;
+            if n == 0 {
+                return Ok(());
+            }
+
+            let mut len = n;
+            let mut close = false;
+            if let Some(limit) = self.faults.drop_after_bytes {
+                let left = limit.saturating_sub(self.bytes);
+                if len as u64 >= left {
+                    len = left as usize;
+                    close = true;
+                }
+            }
+            let corrupt = match self.direction {
+                Direction::ToBus => None,
+                Direction::ToClient => self.faults.corrupt_message,
+            };
+            starts.clear();
+            let end = self.framer.scan(
+                &mut buf[..len],
+                corrupt,
+                self.faults.drop_after_messages,
+                &mut self.rng,
+                &mut starts,
+            );
+            if let Some(end) = end {
+                len = end;
+                close = true;
+            }
+
+            self.bytes += len as u64;
+            self.write(&buf[..len], &fds, &starts)?;
+            if close {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes data in chunks, passing file descriptors with the first one.
+    fn write(&mut self, buf: &[u8], fds: &[OwnedFd], starts: &[usize]) -> Result<()> {
+        let mut fds: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+        let mut pos = 0;
+        for (end, delay) in self.chunker.split(buf.len(), starts) {
+            if delay != Duration::ZERO {
+                thread::sleep(delay);
+            }
+            while pos < end {
+                pos += sys::send_with_fds(self.to.as_raw_fd(), &buf[pos..end], &fds)?;
+                fds.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits forwarded data into chunks and chooses delays preceding them.
+struct Chunker {
+    rng: Rng,
+    chunk_size: Option<usize>,
+    latency: Option<(Duration, Duration)>,
+    /// Remaining length of the current chunk.
+    left: usize,
+}
+
+impl Chunker {
+    fn new(faults: &FaultConfig, rng: Rng) -> Chunker {
+        Chunker {
+            rng,
+            chunk_size: faults.chunk_size,
+            latency: faults.latency,
+            left: 0,
+        }
+    }
+
+    /// Returns ends of chunks of the next `len` bytes, each with a delay to
+    /// wait before writing it. The `starts` are offsets of messages starting
+    /// within the data, in increasing order. Chunks continue across calls.
+    fn split(&mut self, len: usize, starts: &[usize]) -> Vec<(usize, Duration)> {
+        let mut chunks = Vec::new();
+        let mut starts = starts.iter().copied().peekable();
+        let mut pos = 0;
+        while pos < len {
+            let mut delay = Duration::ZERO;
+            if starts.peek() == Some(&pos) {
+                starts.next();
+                if let Some((min, max)) = self.latency {
+                    delay = self.rng.duration(min, max);
+                }
+            }
+            if self.left == 0 {
+                self.left = match self.chunk_size {
+                    Some(max) => 1 + self.rng.below(max as u64) as usize,
+                    None => usize::MAX,
+                };
+            }
+            let mut end = pos + self.left.min(len - pos);
+            if let Some(&start) = starts.peek() {
+                end = end.min(start);
+            }
+            self.left -= end - pos;
+            chunks.push((end, delay));
+            pos = end;
+        }
+        chunks
+    }
+}
+
+/// Finds boundaries of messages in one direction of a connection.
+struct Framer {
+    state: State,
+    direction: Direction,
+    /// Current authentication line.
+    line: Vec<u8>,
+    /// Fixed part of the current message header.
+    header: Vec<u8>,
+    /// Remaining length of the current message after the fixed header.
+    remaining: usize,
+    /// Offset of the byte to corrupt in the rest of the current message.
+    corrupt_at: Option<usize>,
+    /// Number of complete messages.
+    messages: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// The nul byte sent by the client before authentication.
+    Nul,
+    /// Authentication commands or replies, each terminated by "\r\n".
+    Auth,
+    /// The fixed part of a message header.
+    Header,
+    /// The rest of a message.
+    Body,
+    /// Data which is not understood and forwarded as is.
+    Unknown,
+}
+
+impl Framer {
+    fn new(direction: Direction) -> Framer {
+        Framer {
+            state: match direction {
+                Direction::ToBus => State::Nul,
+                Direction::ToClient => State::Auth,
+            },
+            direction,
+            line: Vec::new(),
+            header: Vec::new(),
+            remaining: 0,
+            corrupt_at: None,
+            messages: 0,
+        }
+    }
+
+    /// Processes received data, corrupting the message with given index and
+    /// appending offsets of messages starting within it to `starts`.
+    /// Returns the length of data to forward before closing the connection
+    /// if the given number of messages is reached.
+    fn scan(
+        &mut self,
+        data: &mut [u8],
+        corrupt: Option<u64>,
+        drop_after: Option<u64>,
+        rng: &mut Rng,
+        starts: &mut Vec<usize>,
+    ) -> Option<usize> {
+        let mut i = 0;
+        while i < data.len() {
+            match self.state {
+                State::Nul => {
+                    i += 1;
+                    self.state = State::Auth;
+                }
+                State::Auth => {
+                    let b = data[i];
+                    // Replies of the bus never start like messages do, with
+                    // an endianness marker. The client ends with BEGIN.
+                    if self.direction == Direction::ToClient
+                        && self.line.is_empty()
+                        && (b == b'l' || b == b'B')
+                    {
+                        self.state = State::Header;
+                        continue;
+                    }
+                    self.line.push(b);
+                    i += 1;
+                    if b == b'\n' {
+                        if self.line == b"BEGIN\r\n" {
+                            self.state = State::Header;
+                        }
+                        self.line.clear();
+                    } else if self.line.len() > MAX_LINE_LEN {
+                        self.state = State::Unknown;
+                    }
+                }
+                State::Header => {
+                    if self.header.is_empty() {
+                        if drop_after == Some(self.messages) {
+                            return Some(i);
+                        }
+                        starts.push(i);
+                    }
+                    let n = (16 - self.header.len()).min(data.len() - i);
+                    self.header.extend_from_slice(&data[i..i + n]);
+                    i += n;
+                    if self.header.len() < 16 {
+                        continue;
+                    }
+                    let mut header = [0u8; 16];
+                    header.copy_from_slice(&self.header);
+                    self.header.clear();
+                    match Message::length(&header) {
+                        Ok(len) if len > 16 => {
+                            self.remaining = len - 16;
+                            if corrupt == Some(self.messages) {
+                                self.corrupt_at =
+                                    Some(rng.below(self.remaining as u64) as usize);
+                            }
+                            self.state = State::Body;
+                        }
+                        _ => self.state = State::Unknown,
+                    }
+                }
+                State::Body => {
+                    let n = self.remaining.min(data.len() - i);
+                    if let Some(at) = self.corrupt_at {
+                        if at < n {
+                            data[i + at] ^= 1 + rng.below(255) as u8;
+                            self.corrupt_at = None;
+                        } else {
+                            self.corrupt_at = Some(at - n);
+                        }
+                    }
+                    self.remaining -= n;
+                    i += n;
+                    if self.remaining == 0 {
+                        self.messages += 1;
+                        self.state = State::Header;
+                        if drop_after == Some(self.messages) {
+                            return Some(i);
+                        }
+                    }
+                }
+                State::Unknown => i = data.len(),
+            }
+        }
+        None
+    }
+}
+
+/// A pseudo-random number generator, SplitMix64.
+struct Rng(u64);
+
+impl Rng {
+    /// Returns a generator for one of the streams derived from a seed.
+    fn new(seed: u64, stream: u64) -> Rng {
+        let mut rng = Rng(seed);
+        rng.0 ^= Rng(stream).next();
+        rng
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number less than `n`, which must be positive.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// Returns a duration between `min` and `max` inclusive.
+    fn duration(&mut self, min: Duration, max: Duration) -> Duration {
+        let range = (max - min).as_nanos().min(u64::MAX as u128 - 1) as u64;
+        min + Duration::from_nanos(self.below(range + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Value;
+
+    fn message(member: &str) -> Vec<u8> {
+        let mut m = Message::method_call(
+            Some("com.example.Test"),
+            "/",
+            "com.example.Test",
+            member,
+        )
+        .arg(Value::String("text".into()));
+        m.serial = 1;
+        m.marshal().unwrap()
+    }
+
+    #[test]
+    fn framing() {
+        let (a, b) = (message("A"), message("B"));
+        let mut rng = Rng::new(1, 0);
+
+        let mut data = b"\0AUTH EXTERNAL 30\r\nNEGOTIATE_UNIX_FD\r\nBEGIN\r\n".to_vec();
+        let auth = data.len();
+        data.extend_from_slice(&a);
+        data.extend_from_slice(&b);
+        // Messages are found regardless of how data is split.
+        let mut framer = Framer::new(Direction::ToBus);
+        let mut starts = Vec::new();
+        let end = (0..data.len()).find_map(|i| {
+            let mut s = Vec::new();
+            let end = framer.scan(&mut data[i..i + 1], None, Some(1), &mut rng, &mut s);
+            starts.extend(s.iter().map(|s| i + s));
+            Some(i + end?)
+        });
+        assert_eq!(Some(auth + a.len()), end);
+        assert_eq!(vec![auth], starts);
+
+        let mut data = b"OK 0123\r\nAGREE_UNIX_FD\r\n".to_vec();
+        let auth = data.len();
+        data.extend_from_slice(&a);
+        data.extend_from_slice(&b);
+        let mut framer = Framer::new(Direction::ToClient);
+        let mut starts = Vec::new();
+        let end = framer.scan(&mut data, None, Some(2), &mut rng, &mut starts);
+        assert_eq!(Some(auth + a.len() + b.len()), end);
+        assert_eq!(vec![auth, auth + a.len()], starts);
+        let mut framer = Framer::new(Direction::ToClient);
+        let end = framer.scan(&mut data, None, Some(0), &mut rng, &mut Vec::new());
+        assert_eq!(Some(auth), end);
+        let mut framer = Framer::new(Direction::ToClient);
+        let end = framer.scan(&mut data, None, Some(3), &mut rng, &mut Vec::new());
+        assert_eq!(None, end);
+    }
+
+    #[test]
+    fn corrupt() {
+        let a = message("A");
+        let corrupted = |seed| {
+            let mut data = [&a[..], &a[..]].concat();
+            Framer::new(Direction::ToClient).scan(
+                &mut data,
+                Some(1),
+                None,
+                &mut Rng::new(seed, 1),
+                &mut Vec::new(),
+            );
+            data
+        };
+        let data = corrupted(1);
+        assert_eq!(data, corrupted(1));
+        assert_eq!(a, data[..a.len()]);
+        let changed: Vec<_> = (0..a.len())
+            .filter(|&i| data[a.len() + i] != a[i])
+            .collect();
+        assert_eq!(1, changed.len());
+        assert!(changed[0] >= 16);
+    }
+
+    /// Chunks and delays do not depend on how the data is split into reads.
+    #[test]
+    fn chunks() {
+        let mut faults = FaultConfig::new(1);
+        faults
+            .chunk_size(5)
+            .latency(Duration::from_millis(1), Duration::from_millis(9));
+        let starts = [0, 12, 30];
+        // Returns ends of chunks and delays with offsets they precede.
+        let split = |reads: &[usize]| {
+            let mut chunker = Chunker::new(&faults, Rng::new(1, 1));
+            let mut ends = Vec::new();
+            let mut delays = Vec::new();
+            let mut offset = 0;
+            for &len in reads {
+                let starts: Vec<_> = starts
+                    .iter()
+                    .filter(|&&s| s >= offset && s < offset + len)
+                    .map(|s| s - offset)
+                    .collect();
+                let mut pos = offset;
+                for (end, delay) in chunker.split(len, &starts) {
+                    if delay > Duration::ZERO {
+                        delays.push((pos, delay));
+                    }
+                    ends.push(offset + end);
+                    pos = offset + end;
+                }
+                offset += len;
+            }
+            (ends, delays)
+        };
+
+        let (ends, delays) = split(&[40]);
+        assert!(ends.contains(&12) && ends.contains(&30));
+        assert!(ends.windows(2).all(|w| w[1] - w[0] <= 5));
+        assert_eq!(
+            vec![0, 12, 30],
+            delays.iter().map(|d| d.0).collect::<Vec<_>>()
+        );
+        for reads in &[&[1, 39][..], &[12, 18, 10], &[7, 7, 7, 7, 7, 5]] {
+            let (mut other, other_delays) = split(reads);
+            assert_eq!(delays, other_delays);
+            // Chunks are additionally split where reads end.
+            let mut offset = 0;
+            for len in reads.iter() {
+                offset += len;
+                if !ends.contains(&offset) {
+                    other.retain(|&end| end != offset);
+                }
+            }
+            assert_eq!(ends, other, "{:?}", reads);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn forward() {
+        use std::io::{Read, Write};
+
+        let dir = tempfile::tempdir().unwrap();
+        let bus_address = UnixAddress::Path(dir.path().join("bus"));
+        let bus = bus_address.bind().unwrap();
+        let address = UnixAddress::Path(dir.path().join("proxy"));
+        let mut proxy =
+            Proxy::start(bus_address, address, FaultConfig::new(1).chunk_size(2))
+                .unwrap();
+        let client = proxy.address.connect().unwrap();
+        let (server, _) = bus.accept().unwrap();
+
+        let (mut local, remote) = UnixStream::pair().unwrap();
+        sys::send_with_fds(client.as_raw_fd(), b"\0AUTH", &[remote.as_raw_fd()])
+            .unwrap();
+
+        let mut data = Vec::new();
+        let mut fds = Vec::new();
+        while data.len() < 5 {
+            let mut buf = [0u8; 16];
+            let n = sys::recv_with_fds(server.as_raw_fd(), &mut buf, &mut fds).unwrap();
+            assert!(n > 0);
+            data.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(b"\0AUTH", &data[..]);
+        assert_eq!(1, fds.len());
+        UnixStream::from(fds.pop().unwrap())
+            .write_all(b"x")
+            .unwrap();
+        let mut buf = [0u8];
+        local.read_exact(&mut buf).unwrap();
+        assert_eq!(b"x", &buf);
+
+        // Closing the bus side closes the client side as well.
+        drop(server);
+        assert_eq!(0, (&client).read(&mut buf).unwrap());
+        proxy.stop();
+    }
+}
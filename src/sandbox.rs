@@ -0,0 +1,595 @@
+//! Running the daemon inside fresh Linux namespaces.
+
+use std::collections::HashSet;
+use std::ffi::{CString, OsStr};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Paths made available inside the sandbox by default.
+const SYSTEM_PATHS: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/libx32",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/nsswitch.conf",
+    "/etc/ld.so.cache",
+    "/etc/ld.so.conf",
+    "/etc/ld.so.conf.d",
+    "/etc/localtime",
+    "/etc/machine-id",
+    "/var/lib/dbus",
+];
+
+/// Devices made available inside the sandbox.
+const DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/urandom"];
+
+/// An isolated environment for the daemon process and services it activates.
+///
+/// The daemon is started in new user, mount, PID, IPC and (optionally)
+/// network namespaces. Inside the user namespace the current user and group
+/// are mapped onto themselves, so no privileges are required.
+///
+/// The root of the mount namespace is an empty tmpfs, into which are mounted
+/// read-only: system directories (`/usr`, `/lib` and so on), the user and
+/// group databases and dynamic linker configuration from `/etc`, the
+/// installation prefix of the daemon program, executables of added services
+/// and any paths added with [`bind`](Sandbox::bind). The daemon configuration
+/// directory is mounted read-write. Additionally, `/dev` contains only the
+/// `null`, `zero` and `urandom` devices, fresh `/proc` is mounted and `/tmp`
+/// is an empty tmpfs.
+///
+/// In particular, sockets of the host session and system bus are not
+/// accessible from within the sandbox. Sockets created inside the sandbox
+/// are not accessible from outside of it either, so the daemon cannot listen
+/// on addresses with a path other than the default one.
+///
+/// Available on Linux only.
+///
+/// # Examples
+///
+/// ```no_run
+/// use dbus_launch::{Launcher, Sandbox};
+///
+/// let daemon = Launcher::daemon()
+///     .sandbox(Sandbox::new().bind("/opt/test-services").clone())
+///     .service("com.example.Test", "/opt/test-services/test")
+///     .launch()
+///     .expect("failed to launch dbus-daemon");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    network: bool,
+    binds: Vec<PathBuf>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sandbox {
+    /// Returns a new sandbox configuration with all namespaces enabled.
+    pub fn new() -> Sandbox {
+        Sandbox {
+            network: true,
+            binds: Vec::new(),
+        }
+    }
+
+    /// Controls whether the daemon is started in a new network namespace.
+    ///
+    /// Enabled by default. With network namespace the daemon can only listen
+    /// on the default address, since neither TCP nor abstract sockets would
+    /// be reachable from outside.
+    pub fn network(&mut self, isolate: bool) -> &mut Self {
+        self.network = isolate;
+        self
+    }
+
+    /// Makes given path available inside the sandbox in read-only mode.
+    pub fn bind<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.binds.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub(crate) fn isolates_network(&self) -> bool {
+        self.network
+    }
+
+    /// Prepares the sandbox for use after fork, when memory allocation is not
+    /// allowed.
+    ///
+    /// The `root` is an empty directory used as the mount point for the new
+    /// root. The `config_dir` is made available in read-write mode, and
+    /// `paths` in read-only mode. The `uid` and `gid` are those of the process
+    /// entering the sandbox.
+    pub(crate) fn prepare(
+        &self,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+        program: &OsStr,
+        root: &Path,
+        config_dir: &Path,
+        paths: &[&Path],
+    ) -> Result<Prepared> {
+        let mut p = Prepared {
+            network: self.network,
+            uid_map: cstring(format!("{0} {0} 1\n", uid))?,
+            gid_map: cstring(format!("{0} {0} 1\n", gid))?,
+            root: cstring(root)?,
+            old_root: cstring(root.join(&OLD_ROOT[1..]))?,
+            steps: Vec::new(),
+            created: HashSet::new(),
+            bound: Vec::new(),
+        };
+
+        for path in SYSTEM_PATHS {
+            p.bind(root, Path::new(path), Mode::ReadOnly)?;
+        }
+        if let Some(prefix) = program_prefix(program) {
+            p.bind(root, &prefix, Mode::ReadOnly)?;
+        }
+        for path in paths
+            .iter()
+            .copied()
+            .chain(self.binds.iter().map(|p| p.as_path()))
+        {
+            if let Ok(path) = path.canonicalize() {
+                p.bind(root, &path, Mode::ReadOnly)?;
+            }
+        }
+        // Device nodes remain writable on a read-only mount.
+        for path in DEVICES {
+            p.bind(root, Path::new(path), Mode::ReadOnly)?;
+        }
+        p.mkdir(root, Path::new("/proc"))?;
+        p.mkdir(root, Path::new("/tmp"))?;
+        p.steps.push(Step::Tmpfs(cstring(root.join("tmp"))?));
+        // Not recursive, since the new root itself is mounted there.
+        p.bind(root, config_dir, Mode::NonRecursive)?;
+        p.mkdir(root, Path::new(OLD_ROOT))?;
+        Ok(p)
+    }
+}
+
+/// Location of the old root after pivot_root, relative to the new root.
+const OLD_ROOT: &str = "/.old-root";
+
+/// A sandbox ready to be entered in a child process.
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) struct Prepared {
+    network: bool,
+    uid_map: CString,
+    gid_map: CString,
+    root: CString,
+    old_root: CString,
+    steps: Vec<Step>,
+    /// Paths within new root that are created by earlier steps.
+    created: HashSet<PathBuf>,
+    /// Paths that are bind mounted by earlier steps.
+    bound: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    ReadOnly,
+    /// Read-write without submounts.
+    NonRecursive,
+}
+
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+enum Step {
+    Mkdir(CString),
+    File(CString),
+    Symlink {
+        target: CString,
+        link: CString,
+    },
+    Bind {
+        src: CString,
+        dst: CString,
+        mode: Mode,
+    },
+    Tmpfs(CString),
+}
+
+fn cstring<S: AsRef<OsStr>>(s: S) -> Result<CString> {
+    CString::new(s.as_ref().as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains nul byte"))
+}
+
+/// Returns the installation prefix of a program found in PATH, e.g.,
+/// `/opt/dbus` for `/opt/dbus/bin/dbus-daemon`.
+fn program_prefix(program: &OsStr) -> Option<PathBuf> {
+    let program = Path::new(program);
+    let path = if program.components().count() > 1 {
+        program.to_path_buf()
+    } else {
+        let paths = std::env::var_os("PATH")?;
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file())?
+    };
+    let path = path.canonicalize().ok()?;
+    let dir = path.parent()?;
+    let prefix = match dir.file_name() {
+        Some(name) if name == "bin" || name == "sbin" => dir.parent()?,
+        _ => dir,
+    };
+    if prefix == Path::new("/") {
+        None
+    } else {
+        Some(prefix.to_path_buf())
+    }
+}
+
+impl Prepared {
+    /// Adds steps creating directory at given path within new root, together
+    /// with its ancestors.
+    fn mkdir(&mut self, root: &Path, path: &Path) -> Result<()> {
+        let mut dst = root.to_path_buf();
+        for component in path.strip_prefix("/").unwrap_or(path).components() {
+            dst.push(component);
+            if self.created.insert(dst.clone()) {
+                self.steps.push(Step::Mkdir(cstring(&dst)?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds steps making path available within new root, if it exists.
+    fn bind(&mut self, root: &Path, src: &Path, mode: Mode) -> Result<()> {
+        if !src.is_absolute() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("sandbox path is not absolute: {}", src.display()),
+            ));
+        }
+        if self.bound.iter().any(|bound| src.starts_with(bound)) {
+            return Ok(());
+        }
+        let metadata = match src.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let dst = root.join(src.strip_prefix("/").unwrap());
+        if let Some(parent) = src.parent() {
+            self.mkdir(root, parent)?;
+        }
+        if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(src)?;
+            if self.created.insert(dst.clone()) {
+                self.steps.push(Step::Symlink {
+                    target: cstring(&target)?,
+                    link: cstring(&dst)?,
+                });
+            }
+            // Make sure the target is available as well.
+            let target = src.parent().unwrap().join(target);
+            return self.bind(root, &target, mode);
+        }
+        if metadata.is_dir() {
+            self.mkdir(root, src)?;
+        } else if self.created.insert(dst.clone()) {
+            self.steps.push(Step::File(cstring(&dst)?));
+        }
+        self.steps.push(Step::Bind {
+            src: cstring(src)?,
+            dst: cstring(&dst)?,
+            mode,
+        });
+        self.bound.push(src.to_path_buf());
+        Ok(())
+    }
+
+    /// Enters the sandbox. Must be called in a child process after fork.
+    ///
+    /// Returns in a great-grandchild process, a child of the init process of
+    /// the new PID namespace. Both the child and the init process wait for it
+    /// and exit with the same status.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn enter(&self) -> Result<()> {
+        use libc::{c_int, c_ulong};
+        use std::ptr;
+
+        fn check(ret: c_int) -> Result<()> {
+            if ret == -1 {
+                Err(Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn write_file(path: &[u8], contents: &[u8]) -> Result<()> {
+            let fd = unsafe {
+                libc::open(path.as_ptr().cast(), libc::O_WRONLY | libc::O_CLOEXEC)
+            };
+            check(fd)?;
+            let n = unsafe { libc::write(fd, contents.as_ptr().cast(), contents.len()) };
+            unsafe { libc::close(fd) };
+            if n == -1 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        fn mount(
+            src: *const libc::c_char,
+            dst: *const libc::c_char,
+            fstype: *const libc::c_char,
+            flags: c_ulong,
+        ) -> Result<()> {
+            check(unsafe { libc::mount(src, dst, fstype, flags, ptr::null()) })
+        }
+
+        let mut flags = libc::CLONE_NEWUSER
+            | libc::CLONE_NEWNS
+            | libc::CLONE_NEWPID
+            | libc::CLONE_NEWIPC;
+        if self.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        check(unsafe { libc::unshare(flags) })?;
+
+        write_file(b"/proc/self/setgroups\0", b"deny")?;
+        write_file(b"/proc/self/uid_map\0", self.uid_map.as_bytes())?;
+        write_file(b"/proc/self/gid_map\0", self.gid_map.as_bytes())?;
+
+        let none = ptr::null();
+        mount(
+            none,
+            b"/\0".as_ptr().cast(),
+            none,
+            libc::MS_REC | libc::MS_PRIVATE,
+        )?;
+        mount(
+            b"tmpfs\0".as_ptr().cast(),
+            self.root.as_ptr(),
+            b"tmpfs\0".as_ptr().cast(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+        )?;
+
+        for step in &self.steps {
+            match step {
+                Step::Mkdir(path) => {
+                    if unsafe { libc::mkdir(path.as_ptr(), 0o755) } == -1 {
+                        let err = Error::last_os_error();
+                        if err.raw_os_error() != Some(libc::EEXIST) {
+                            return Err(err);
+                        }
+                    }
+                }
+                Step::File(path) => {
+                    let fd = unsafe {
+                        libc::open(
+                            path.as_ptr(),
+                            libc::O_RDONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                            0o644,
+                        )
+                    };
+                    check(fd)?;
+                    unsafe { libc::close(fd) };
+                }
+                Step::Symlink { target, link } => {
+                    check(unsafe { libc::symlink(target.as_ptr(), link.as_ptr()) })?;
+                }
+                Step::Bind { src, dst, mode } => {
+                    let mut flags = libc::MS_BIND;
+                    if *mode != Mode::NonRecursive {
+                        flags |= libc::MS_REC;
+                    }
+                    mount(src.as_ptr(), dst.as_ptr(), none, flags)?;
+                    if *mode == Mode::ReadOnly {
+                        // Remount must preserve flags locked by the
+                        // user namespace.
+                        let mut st = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+                        check(unsafe { libc::statvfs(dst.as_ptr(), st.as_mut_ptr()) })?;
+                        let st = unsafe { st.assume_init() };
+                        let mut flags =
+                            libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+                        for &(st_flag, ms_flag) in &[
+                            (libc::ST_NOSUID, libc::MS_NOSUID),
+                            (libc::ST_NODEV, libc::MS_NODEV),
+                            (libc::ST_NOEXEC, libc::MS_NOEXEC),
+                            (libc::ST_NOATIME, libc::MS_NOATIME),
+                            (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+                            (libc::ST_RELATIME, libc::MS_RELATIME),
+                        ] {
+                            if st.f_flag & st_flag != 0 {
+                                flags |= ms_flag;
+                            }
+                        }
+                        mount(none, dst.as_ptr(), none, flags)?;
+                    }
+                }
+                Step::Tmpfs(path) => {
+                    mount(
+                        b"tmpfs\0".as_ptr().cast(),
+                        path.as_ptr(),
+                        b"tmpfs\0".as_ptr().cast(),
+                        libc::MS_NOSUID | libc::MS_NODEV,
+                    )?;
+                }
+            }
+        }
+
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_pivot_root,
+                self.root.as_ptr(),
+                self.old_root.as_ptr(),
+            ) as c_int
+        })?;
+        check(unsafe { libc::chdir(b"/\0".as_ptr().cast()) })?;
+
+        // The parent of the init process is outside of its PID namespace,
+        // where getppid returns zero, so it is watched through a pidfd.
+        let parent =
+            unsafe { libc::syscall(libc::SYS_pidfd_open, libc::getpid(), 0) as c_int };
+
+        // The first process forked after unsharing PID namespace becomes its
+        // init process.
+        let pid = unsafe { libc::fork() };
+        check(pid)?;
+        if pid != 0 {
+            wait_and_exit(pid);
+        }
+
+        // Terminate if the parent is killed, so that the whole PID namespace
+        // is torn down. Fail if it was killed before the signal was set up.
+        check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+        if parent != -1 {
+            let mut pfd = libc::pollfd {
+                fd: parent,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let exited = unsafe { libc::poll(&mut pfd, 1, 0) } != 0;
+            unsafe { libc::close(parent) };
+            if exited {
+                return Err(Error::from_raw_os_error(libc::ESRCH));
+            }
+        }
+
+        // Signals without a handler are discarded by the init process, so the
+        // daemon is run as its child, where it can be terminated before it
+        // installs its own handlers.
+        let pid = unsafe { libc::fork() };
+        check(pid)?;
+        if pid != 0 {
+            wait_and_exit(pid);
+        }
+        check(unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) })?;
+        if unsafe { libc::getppid() } != 1 {
+            return Err(Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        // Mounting proc requires a fully visible proc mount in the mount
+        // namespace, so it has to be done before detaching the old root.
+        if mount(
+            b"proc\0".as_ptr().cast(),
+            b"/proc\0".as_ptr().cast(),
+            b"proc\0".as_ptr().cast(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        )
+        .is_err()
+        {
+            // Fall back to the proc of the parent PID namespace.
+            mount(
+                b"/.old-root/proc\0".as_ptr().cast(),
+                b"/proc\0".as_ptr().cast(),
+                none,
+                libc::MS_BIND | libc::MS_REC,
+            )?;
+        }
+
+        let old_root = b"/.old-root\0".as_ptr().cast();
+        check(unsafe { libc::umount2(old_root, libc::MNT_DETACH) })?;
+        check(unsafe { libc::rmdir(old_root) })?;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn enter(&self) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "sandbox is supported on Linux only",
+        ))
+    }
+}
+
+/// Pid of the process signals are forwarded to.
+#[cfg(target_os = "linux")]
+static CHILD: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+#[cfg(target_os = "linux")]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let pid = CHILD.load(std::sync::atomic::Ordering::Relaxed);
+    unsafe { libc::kill(pid, signal) };
+}
+
+/// Forwards termination signals to the child and waits for it to exit. Then
+/// exits with the same status.
+#[cfg(target_os = "linux")]
+fn wait_and_exit(pid: libc::pid_t) -> ! {
+    CHILD.store(pid, std::sync::atomic::Ordering::Relaxed);
+    let handler = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for &signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+        unsafe { libc::signal(signal, handler) };
+    }
+
+    // Don't keep the daemon's file descriptors open.
+    crate::sys::close_from(3);
+
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } == pid {
+            break;
+        }
+        if Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            unsafe { libc::_exit(1) };
+        }
+    }
+    unsafe {
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+            libc::_exit(128 + signal);
+        }
+        libc::_exit(libc::WEXITSTATUS(status))
+    }
+}
+
+/// Translates a PID from the PID namespace of a sandbox into a PID in the
+/// current namespace. The `outer` is the PID of the process waiting for the
+/// sandbox init process.
+pub(crate) fn host_pid(outer: libc::pid_t, pid: libc::pid_t) -> Result<libc::pid_t> {
+    let children =
+        std::fs::read_to_string(format!("/proc/{0}/task/{0}/children", outer))?;
+    let init = match children.split_whitespace().next() {
+        Some(init) => init,
+        None => return Err(Error::new(ErrorKind::NotFound, "sandbox init not found")),
+    };
+    let ns = std::fs::read_link(format!("/proc/{}/ns/pid", init))?;
+    for entry in std::fs::read_dir("/proc")? {
+        let entry = entry?;
+        let candidate = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(candidate) => candidate,
+            None => continue,
+        };
+        if std::fs::read_link(entry.path().join("ns/pid"))
+            .ok()
+            .as_ref()
+            != Some(&ns)
+        {
+            continue;
+        }
+        let status = match std::fs::read_to_string(entry.path().join("status")) {
+            Ok(status) => status,
+            Err(_) => continue,
+        };
+        let nspid = status
+            .lines()
+            .find_map(|line| line.strip_prefix("NSpid:"))
+            .and_then(|pids| pids.split_whitespace().last())
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+        if nspid == Some(pid) {
+            return Ok(candidate);
+        }
+    }
+    Err(Error::new(
+        ErrorKind::NotFound,
+        format!("process {} not found in sandbox", pid),
+    ))
+}
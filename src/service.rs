@@ -0,0 +1,91 @@
+//! Services implemented as functions in the launching executable.
+//!
+//! The service file executes the current executable again with a marker
+//! argument holding the service name. The function is then looked up by name
+//! among those passed to [`service_main`], which is the only place where
+//! names are bound to functions.
+
+use std::ffi::OsString;
+use std::io;
+use std::sync::Mutex;
+
+/// Prefix of the argument identifying the service function.
+const MARKER: &str = "--dbus-launch-service=";
+
+/// Names of services passed to [`service_main`] in the current process.
+static SERVICES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Runs a service added with [`Launcher::service_fn`] if the current process
+/// was started to activate it, and returns otherwise.
+///
+/// The `services` lists all service functions of the executable together
+/// with their names, which [`Launcher::service_fn`] refers to. It should be
+/// called at the beginning of `main` of the executable that launches the
+/// daemon. When the service is run, the process exits once the service
+/// function returns, with a zero exit code. If the process was started for a
+/// service missing from `services`, it exits with a non-zero exit code. For
+/// integration tests this requires a test target with `harness = false`.
+///
+/// # Examples
+///
+/// ```no_run
+/// fn service() {
+///     // Connect to the bus at DBUS_STARTER_ADDRESS and acquire the name.
+/// }
+///
+/// fn main() {
+///     dbus_launch::service_main(&[("com.example.Test", service)]);
+///
+///     let daemon = dbus_launch::Launcher::daemon()
+///         .service_fn("com.example.Test")
+///         .launch()
+///         .expect("failed to launch dbus-daemon");
+///     // ...
+/// }
+/// ```
+///
+/// [`Launcher::service_fn`]: crate::Launcher::service_fn
+pub fn service_main(services: &[(&str, fn())]) {
+    let name = match std::env::args_os()
+        .nth(1)
+        .and_then(|arg| arg.into_string().ok())
+        .and_then(|arg| arg.strip_prefix(MARKER).map(str::to_owned))
+    {
+        Some(name) => name,
+        None => {
+            let mut registered = SERVICES.lock().unwrap();
+            registered.extend(services.iter().map(|&(name, _)| name.to_owned()));
+            return;
+        }
+    };
+    match services.iter().find(|&&(n, _)| n == name) {
+        Some(&(_, service)) => {
+            service();
+            std::process::exit(0);
+        }
+        None => {
+            eprintln!("dbus-launch: no service function for {}", name);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Checks that a service function was passed under given name to
+/// [`service_main`].
+pub(crate) fn check(name: &str) -> io::Result<()> {
+    if SERVICES.lock().unwrap().iter().any(|n| n == name) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("no service function for {} passed to service_main", name),
+    ))
+}
+
+/// Returns arguments executing the service function with given name.
+pub(crate) fn argv(name: &str) -> io::Result<Vec<OsString>> {
+    Ok(vec![
+        std::env::current_exe()?.into_os_string(),
+        format!("{}{}", MARKER, name).into(),
+    ])
+}
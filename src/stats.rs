@@ -0,0 +1,382 @@
+//! Bus statistics from the org.freedesktop.DBus.Debug.Stats interface.
+
+use crate::client::{Connection, MethodError, BUS_NAME, BUS_PATH};
+use crate::message::Value;
+use crate::BusName;
+use std::io::{Error, ErrorKind, Result};
+
+const INTERFACE: &str = "org.freedesktop.DBus.Debug.Stats";
+
+/// A snapshot of message bus statistics.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    active_connections: u32,
+    incomplete_connections: u32,
+    match_rules: u32,
+    peak_match_rules: u32,
+    peak_match_rules_per_connection: u32,
+    bus_names: u32,
+    peak_bus_names: u32,
+    peak_bus_names_per_connection: u32,
+    connections: Vec<ConnectionStats>,
+}
+
+impl Stats {
+    /// Returns the number of authenticated connections.
+    pub fn active_connections(&self) -> u32 {
+        self.active_connections
+    }
+
+    /// Returns the number of connections that were not authenticated yet.
+    pub fn incomplete_connections(&self) -> u32 {
+        self.incomplete_connections
+    }
+
+    /// Returns the number of match rules of all connections.
+    pub fn match_rules(&self) -> u32 {
+        self.match_rules
+    }
+
+    /// Returns the highest number of match rules of all connections.
+    pub fn peak_match_rules(&self) -> u32 {
+        self.peak_match_rules
+    }
+
+    /// Returns the highest number of match rules of a single connection.
+    pub fn peak_match_rules_per_connection(&self) -> u32 {
+        self.peak_match_rules_per_connection
+    }
+
+    /// Returns the number of names owned by all connections, including
+    /// unique names.
+    pub fn bus_names(&self) -> u32 {
+        self.bus_names
+    }
+
+    /// Returns the highest number of names owned by all connections.
+    pub fn peak_bus_names(&self) -> u32 {
+        self.peak_bus_names
+    }
+
+    /// Returns the highest number of names owned by a single connection.
+    pub fn peak_bus_names_per_connection(&self) -> u32 {
+        self.peak_bus_names_per_connection
+    }
+
+    /// Returns statistics of individual connections.
+    pub fn connections(&self) -> &[ConnectionStats] {
+        &self.connections
+    }
+
+    /// Returns statistics of the connection with given unique name.
+    pub fn connection(&self, unique_name: &str) -> Option<&ConnectionStats> {
+        self.connections
+            .iter()
+            .find(|c| c.unique_name.as_str() == unique_name)
+    }
+
+    pub(crate) fn retain_connections<F>(&mut self, f: F)
+    where
+        F: FnMut(&ConnectionStats) -> bool,
+    {
+        self.connections.retain(f);
+    }
+}
+
+/// Statistics of a single connection to the message bus.
+///
+/// Counts of messages, bytes and file descriptors refer to those queued by
+/// the bus, i.e., incoming ones not processed yet, and outgoing ones not
+/// yet received by the peer. The peaks are the highest sizes of the queues.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    unique_name: BusName,
+    match_rules: Vec<String>,
+    peak_match_rules: u32,
+    bus_names: u32,
+    peak_bus_names: u32,
+    incoming_messages: u32,
+    incoming_bytes: u32,
+    incoming_fds: u32,
+    peak_incoming_bytes: u32,
+    peak_incoming_fds: u32,
+    outgoing_messages: u32,
+    outgoing_bytes: u32,
+    outgoing_fds: u32,
+    peak_outgoing_bytes: u32,
+    peak_outgoing_fds: u32,
+}
+
+impl ConnectionStats {
+    fn new(unique_name: BusName, match_rules: Vec<String>) -> ConnectionStats {
+        ConnectionStats {
+            unique_name,
+            match_rules,
+            peak_match_rules: 0,
+            bus_names: 0,
+            peak_bus_names: 0,
+            incoming_messages: 0,
+            incoming_bytes: 0,
+            incoming_fds: 0,
+            peak_incoming_bytes: 0,
+            peak_incoming_fds: 0,
+            outgoing_messages: 0,
+            outgoing_bytes: 0,
+            outgoing_fds: 0,
+            peak_outgoing_bytes: 0,
+            peak_outgoing_fds: 0,
+        }
+    }
+
+    /// Returns the unique name of the connection.
+    pub fn unique_name(&self) -> &BusName {
+        &self.unique_name
+    }
+
+    /// Returns match rules added by the connection.
+    pub fn match_rules(&self) -> &[String] {
+        &self.match_rules
+    }
+
+    /// Returns the highest number of match rules of the connection.
+    pub fn peak_match_rules(&self) -> u32 {
+        self.peak_match_rules
+    }
+
+    /// Returns the number of names owned by the connection, including its
+    /// unique name.
+    pub fn bus_names(&self) -> u32 {
+        self.bus_names
+    }
+
+    /// Returns the highest number of names owned by the connection.
+    pub fn peak_bus_names(&self) -> u32 {
+        self.peak_bus_names
+    }
+
+    /// Returns the number of messages queued from the connection.
+    pub fn incoming_messages(&self) -> u32 {
+        self.incoming_messages
+    }
+
+    /// Returns the size of messages queued from the connection.
+    pub fn incoming_bytes(&self) -> u32 {
+        self.incoming_bytes
+    }
+
+    /// Returns the number of file descriptors queued from the connection.
+    pub fn incoming_fds(&self) -> u32 {
+        self.incoming_fds
+    }
+
+    /// Returns the highest size of messages queued from the connection.
+    pub fn peak_incoming_bytes(&self) -> u32 {
+        self.peak_incoming_bytes
+    }
+
+    /// Returns the highest number of file descriptors queued from the
+    /// connection.
+    pub fn peak_incoming_fds(&self) -> u32 {
+        self.peak_incoming_fds
+    }
+
+    /// Returns the number of messages queued for the connection.
+    pub fn outgoing_messages(&self) -> u32 {
+        self.outgoing_messages
+    }
+
+    /// Returns the size of messages queued for the connection.
+    pub fn outgoing_bytes(&self) -> u32 {
+        self.outgoing_bytes
+    }
+
+    /// Returns the number of file descriptors queued for the connection.
+    pub fn outgoing_fds(&self) -> u32 {
+        self.outgoing_fds
+    }
+
+    /// Returns the highest size of messages queued for the connection.
+    pub fn peak_outgoing_bytes(&self) -> u32 {
+        self.peak_outgoing_bytes
+    }
+
+    /// Returns the highest number of file descriptors queued for the
+    /// connection.
+    pub fn peak_outgoing_fds(&self) -> u32 {
+        self.peak_outgoing_fds
+    }
+}
+
+/// Queries statistics of the bus and all its connections.
+pub(crate) fn query(conn: &mut Connection) -> Result<Stats> {
+    let mut stats = Stats::default();
+    for (key, value) in dict(&stats_call(conn, "GetStats", &[])?, "GetStats")? {
+        let value = match *value {
+            Value::Uint32(value) => value,
+            _ => continue,
+        };
+        match key {
+            "ActiveConnections" => stats.active_connections = value,
+            "IncompleteConnections" => stats.incomplete_connections = value,
+            "MatchRules" => stats.match_rules = value,
+            "PeakMatchRules" => stats.peak_match_rules = value,
+            "PeakMatchRulesPerConnection" => {
+                stats.peak_match_rules_per_connection = value
+            }
+            "BusNames" => stats.bus_names = value,
+            "PeakBusNames" => stats.peak_bus_names = value,
+            "PeakBusNamesPerConnection" => stats.peak_bus_names_per_connection = value,
+            // Memory pool statistics are not interesting outside of the bus.
+            _ => {}
+        }
+    }
+
+    let reply = stats_call(conn, "GetAllMatchRules", &[])?;
+    for (name, rules) in match_rules(&reply)? {
+        match connection_stats(conn, name, rules) {
+            Ok(connection) => stats.connections.push(connection),
+            // The connection was closed in the meantime.
+            Err(e)
+                if MethodError::from_io(&e).map(MethodError::name)
+                    == Some("org.freedesktop.DBus.Error.NameHasNoOwner") => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(stats)
+}
+
+fn connection_stats(
+    conn: &mut Connection,
+    name: BusName,
+    match_rules: Vec<String>,
+) -> Result<ConnectionStats> {
+    let args = [Value::String(name.as_str().to_owned())];
+    let reply = stats_call(conn, "GetConnectionStats", &args)?;
+    let mut stats = ConnectionStats::new(name, match_rules);
+    for (key, value) in dict(&reply, "GetConnectionStats")? {
+        let value = match *value {
+            Value::Uint32(value) => value,
+            _ => continue,
+        };
+        match key {
+            "PeakMatchRules" => stats.peak_match_rules = value,
+            "BusNames" => stats.bus_names = value,
+            "PeakBusNames" => stats.peak_bus_names = value,
+            "IncomingMessages" => stats.incoming_messages = value,
+            "IncomingBytes" => stats.incoming_bytes = value,
+            "IncomingFDs" => stats.incoming_fds = value,
+            "PeakIncomingBytes" => stats.peak_incoming_bytes = value,
+            "PeakIncomingFDs" => stats.peak_incoming_fds = value,
+            "OutgoingMessages" => stats.outgoing_messages = value,
+            "OutgoingBytes" => stats.outgoing_bytes = value,
+            "OutgoingFDs" => stats.outgoing_fds = value,
+            "PeakOutgoingBytes" => stats.peak_outgoing_bytes = value,
+            "PeakOutgoingFDs" => stats.peak_outgoing_fds = value,
+            // The match rules are already known in full.
+            _ => {}
+        }
+    }
+    Ok(stats)
+}
+
+fn stats_call(
+    conn: &mut Connection,
+    member: &str,
+    args: &[Value],
+) -> Result<Vec<Value>> {
+    conn.call(BUS_NAME, BUS_PATH, INTERFACE, member, args)
+}
+
+/// Returns entries of a reply with a single a{sv} argument.
+fn dict<'a>(reply: &'a [Value], member: &str) -> Result<Vec<(&'a str, &'a Value)>> {
+    let entries = match reply.first() {
+        Some(Value::Array(_, entries)) => entries,
+        _ => return Err(invalid_reply(member)),
+    };
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Value::DictEntry(key, value) => match (&**key, &**value) {
+                (Value::String(key), Value::Variant(value)) => {
+                    Ok((key.as_str(), &**value))
+                }
+                _ => Err(invalid_reply(member)),
+            },
+            _ => Err(invalid_reply(member)),
+        })
+        .collect()
+}
+
+/// Parses a reply to GetAllMatchRules with a single a{sas} argument.
+fn match_rules(reply: &[Value]) -> Result<Vec<(BusName, Vec<String>)>> {
+    let invalid = || invalid_reply("GetAllMatchRules");
+    let entries = match reply.first() {
+        Some(Value::Array(_, entries)) => entries,
+        _ => return Err(invalid()),
+    };
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Value::DictEntry(key, value) => match (&**key, &**value) {
+                (Value::String(name), Value::Array(_, rules)) => {
+                    let rules = rules
+                        .iter()
+                        .map(|rule| rule.as_str().map(str::to_owned).ok_or_else(invalid))
+                        .collect::<Result<_>>()?;
+                    Ok((BusName::new(name)?, rules))
+                }
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+fn invalid_reply(member: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid reply to {}", member),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, value: Value) -> Value {
+        Value::DictEntry(Box::new(Value::String(key.into())), Box::new(value))
+    }
+
+    #[test]
+    fn parse() {
+        let reply = vec![Value::Array(
+            "{sv}".into(),
+            vec![
+                entry("Serial", Value::Variant(Box::new(Value::Uint32(1)))),
+                entry("MatchRules", Value::Variant(Box::new(Value::Uint32(2)))),
+            ],
+        )];
+        let entries = dict(&reply, "GetStats").unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!(("MatchRules", &Value::Uint32(2)), entries[1]);
+
+        let rule = "type='signal'";
+        let reply = vec![Value::Array(
+            "{sas}".into(),
+            vec![
+                entry(":1.0", Value::Array("s".into(), Vec::new())),
+                entry(
+                    ":1.1",
+                    Value::Array("s".into(), vec![Value::String(rule.into())]),
+                ),
+            ],
+        )];
+        let rules = match_rules(&reply).unwrap();
+        assert_eq!(":1.0", rules[0].0.as_str());
+        assert!(rules[0].1.is_empty());
+        assert_eq!(":1.1", rules[1].0.as_str());
+        assert_eq!(vec![rule.to_owned()], rules[1].1);
+
+        assert!(match_rules(&reply[..0]).is_err());
+    }
+}
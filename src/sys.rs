@@ -1,6 +1,8 @@
 use libc::{self, c_char, c_int};
-use std::io::{Error, Result};
+use std::io::{Error, ErrorKind, Result};
 use std::mem::MaybeUninit;
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::ptr;
 
 /// Sets close on exec flag on given file descriptor.
 pub(crate) fn set_close_on_exec(fd: c_int, close_on_exec: bool) -> Result<()> {
@@ -20,9 +22,167 @@ pub(crate) fn set_close_on_exec(fd: c_int, close_on_exec: bool) -> Result<()> {
 }
 
 /// Sets close on exec flag on all file descriptors >= min.
+///
+/// Safe to use after fork: uses close_range when supported by the kernel,
+/// otherwise enumerates /proc/self/fd, and as the last resort checks every
+/// file descriptor up to RLIMIT_NOFILE.
+pub(crate) fn close_on_exec_from(min: c_int) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        if close_range(min, libc::CLOSE_RANGE_CLOEXEC) {
+            return Ok(());
+        }
+        if let Some(result) = for_each_open_fd(min, |fd| set_close_on_exec(fd, true)) {
+            return result;
+        }
+    }
+    poll_close_on_exec_from(min)
+}
+
+/// A way of marking file descriptors close on exec, which benchmarks can
+/// select instead of the automatic fallback of [`close_on_exec_from`].
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseOnExec {
+    /// close_range with CLOSE_RANGE_CLOEXEC.
+    CloseRange,
+    /// Enumeration of /proc/self/fd with getdents64.
+    ProcSelfFd,
+    /// Checking every file descriptor up to RLIMIT_NOFILE.
+    Poll,
+}
+
+/// Sets close on exec flag on all file descriptors >= min using only given
+/// strategy. Fails with [`ErrorKind::Unsupported`] if it is not available.
+#[doc(hidden)]
+pub fn close_on_exec_using(min: c_int, strategy: CloseOnExec) -> Result<()> {
+    let unsupported = || {
+        Error::new(
+            ErrorKind::Unsupported,
+            format!("{:?} is not supported", strategy),
+        )
+    };
+    match strategy {
+        #[cfg(target_os = "linux")]
+        CloseOnExec::CloseRange if close_range(min, libc::CLOSE_RANGE_CLOEXEC) => Ok(()),
+        #[cfg(target_os = "linux")]
+        CloseOnExec::ProcSelfFd => {
+            for_each_open_fd(min, |fd| set_close_on_exec(fd, true))
+                .unwrap_or_else(|| Err(unsupported()))
+        }
+        CloseOnExec::Poll => poll_close_on_exec_from(min),
+        _ => Err(unsupported()),
+    }
+}
+
+/// Closes all file descriptors >= min, ignoring any errors.
+#[cfg(target_os = "linux")]
+pub(crate) fn close_from(min: c_int) {
+    if close_range(min, 0) {
+        return;
+    }
+    let closed = for_each_open_fd(min, |fd| {
+        unsafe { libc::close(fd) };
+        Ok(())
+    });
+    if closed.is_some() {
+        return;
+    }
+    if let Ok(limit) = get_fd_limit() {
+        for fd in min..limit {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// Applies close_range to all file descriptors >= min. Returns false if it
+/// is not supported.
+#[cfg(target_os = "linux")]
+fn close_range(min: c_int, flags: libc::c_uint) -> bool {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            min as libc::c_uint,
+            libc::c_uint::MAX,
+            flags,
+        )
+    };
+    ret == 0
+}
+
+/// Calls `f` with each open file descriptor >= min, as listed in
+/// /proc/self/fd. Returns None if /proc is unavailable.
+///
+/// Uses getdents64 directly, since opendir allocates memory.
+#[cfg(target_os = "linux")]
+fn for_each_open_fd(
+    min: c_int,
+    mut f: impl FnMut(c_int) -> Result<()>,
+) -> Option<Result<()>> {
+    let dir = unsafe {
+        libc::open(
+            b"/proc/self/fd\0".as_ptr().cast(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if dir == -1 {
+        return None;
+    }
+    let mut buf = [0u8; 4096];
+    let result = loop {
+        let n = unsafe {
+            libc::syscall(libc::SYS_getdents64, dir, buf.as_mut_ptr(), buf.len())
+        };
+        if n == -1 {
+            break Err(Error::last_os_error());
+        }
+        if n == 0 {
+            break Ok(());
+        }
+        let mut offset = 0;
+        let mut result = Ok(());
+        while offset < n as usize {
+            // struct linux_dirent64 { u64 d_ino; i64 d_off; u16 d_reclen;
+            // u8 d_type; char d_name[]; }
+            let entry = &buf[offset..];
+            let reclen = u16::from_ne_bytes([entry[16], entry[17]]) as usize;
+            let name = &entry[19..reclen];
+            offset += reclen;
+            let fd = match parse_fd(name) {
+                Some(fd) if fd >= min && fd != dir => fd,
+                _ => continue,
+            };
+            if let Err(err) = f(fd) {
+                result = Err(err);
+                break;
+            }
+        }
+        if result.is_err() {
+            break result;
+        }
+    };
+    unsafe { libc::close(dir) };
+    Some(result)
+}
+
+/// Parses a NUL terminated decimal file descriptor number.
+#[cfg(target_os = "linux")]
+fn parse_fd(name: &[u8]) -> Option<c_int> {
+    let mut fd: c_int = 0;
+    for &b in name.iter().take_while(|&&b| b != 0) {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        fd = fd.checked_mul(10)?.checked_add((b - b'0') as c_int)?;
+    }
+    Some(fd)
+}
+
+/// Sets close on exec flag on all file descriptors >= min, checking each one
+/// up to RLIMIT_NOFILE.
 // poll on Darwin doesn't set POLLNVAL for closed fds.
 #[cfg(not(target_os = "macos"))]
-pub(crate) fn close_on_exec_from(min: c_int) -> Result<()> {
+fn poll_close_on_exec_from(min: c_int) -> Result<()> {
     let mut pfds = [libc::pollfd {
         fd: 0,
         events: 0,
@@ -41,13 +201,7 @@ pub(crate) fn close_on_exec_from(min: c_int) -> Result<()> {
             pfd.revents = 0;
         }
 
-        if unsafe {
-            libc::poll(
-                pfds.as_mut_ptr() as *mut libc::pollfd,
-                pfds.len() as libc::nfds_t,
-                0,
-            )
-        } == -1
+        if unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, 0) } == -1
         {
             return Err(Error::last_os_error());
         }
@@ -64,9 +218,8 @@ pub(crate) fn close_on_exec_from(min: c_int) -> Result<()> {
     Ok(())
 }
 
-/// Sets close on exec flag on all file descriptors >= min.
 #[cfg(target_os = "macos")]
-pub(crate) fn close_on_exec_from(min: c_int) -> Result<()> {
+fn poll_close_on_exec_from(min: c_int) -> Result<()> {
     for fd in min..get_fd_limit()? {
         if let Err(err) = set_close_on_exec(fd, true) {
             if err.raw_os_error() != Some(libc::EBADF) {
@@ -77,6 +230,40 @@ pub(crate) fn close_on_exec_from(min: c_int) -> Result<()> {
     Ok(())
 }
 
+/// Fills the buffer with random bytes.
+pub(crate) fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    use std::io::Read;
+    std::fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// Returns the name of the user with given uid, or None if it has no entry
+/// in the user database.
+pub(crate) fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut buf = vec![0u8; 1024];
+    loop {
+        let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+        let mut result = ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwuid_r(
+                uid,
+                pwd.as_mut_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        let name = unsafe { std::ffi::CStr::from_ptr((*result).pw_name) };
+        return name.to_str().ok().map(str::to_owned);
+    }
+}
+
 fn get_fd_limit() -> Result<c_int> {
     let mut limit = MaybeUninit::uninit();
     if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) } == -1 {
@@ -106,3 +293,142 @@ pub(crate) unsafe fn execvpe(
     *_NSGetEnviron() = env;
     libc::execvp(file, argv)
 }
+
+/// Maximum number of file descriptors received with a single message.
+const MAX_FDS: usize = 16;
+
+/// Sends data together with file descriptors over a Unix domain socket.
+pub(crate) fn send_with_fds(fd: c_int, buf: &[u8], fds: &[RawFd]) -> Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of_val(fds) as u32) };
+    let mut control = vec![0u8; space as usize];
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of_val(fds) as u32) as _;
+            ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg).cast::<RawFd>(),
+                fds.len(),
+            );
+        }
+    }
+
+    loop {
+        let n = unsafe { libc::sendmsg(fd, &msg, libc::MSG_NOSIGNAL) };
+        if n != -1 {
+            return Ok(n as usize);
+        }
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Receives data from a Unix domain socket, appending any received file
+/// descriptors to `fds`.
+pub(crate) fn recv_with_fds(
+    fd: c_int,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+) -> Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let space =
+        unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<RawFd>()) as u32) };
+    let mut control = vec![0u8; space as usize];
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    // Elsewhere received file descriptors are marked close on exec below.
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+
+    let n = loop {
+        let n = unsafe { libc::recvmsg(fd, &mut msg, flags) };
+        if n != -1 {
+            break n as usize;
+        }
+        let err = Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+
+    #[cfg(not(target_os = "linux"))]
+    let received = fds.len();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET
+                && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+            {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / std::mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    for fd in &fds[received..] {
+        set_close_on_exec(std::os::unix::io::AsRawFd::as_raw_fd(fd), true)?;
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(Error::other("ancillary data truncated"));
+    }
+
+    Ok(n)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn open_fds() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+        let mut fds = Vec::new();
+        for_each_open_fd(fd, |fd| {
+            fds.push(fd);
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+        assert!(fds.contains(&fd));
+        assert!(fds.iter().all(|&n| n >= fd));
+    }
+
+    #[test]
+    fn fd_names() {
+        assert_eq!(Some(0), parse_fd(b"0\0"));
+        assert_eq!(Some(1234), parse_fd(b"1234\0\0\0"));
+        assert_eq!(None, parse_fd(b".\0"));
+        assert_eq!(None, parse_fd(b"..\0"));
+    }
+}
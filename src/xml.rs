@@ -32,7 +32,7 @@ impl<'a> XmlWriter<'a> {
         }
 
         self.indent();
-        self.w.push_str("<");
+        self.w.push('<');
         self.w.push_str(tag);
         self.started += 1;
         self.state = State::Tag;
@@ -43,9 +43,9 @@ impl<'a> XmlWriter<'a> {
             self.w.push_str(">\n");
         }
         self.indent();
-        self.w.push_str("<");
+        self.w.push('<');
         self.w.push_str(tag);
-        self.w.push_str(">");
+        self.w.push('>');
         self.escaped_text(text);
         self.w.push_str("</");
         self.w.push_str(tag);
@@ -77,11 +77,11 @@ impl<'a> XmlWriter<'a> {
     }
 
     pub(crate) fn attr(&mut self, name: &str, value: &str) {
-        self.w.push_str(" ");
+        self.w.push(' ');
         self.w.push_str(name);
         self.w.push_str("=\"");
         self.escaped_attr(value);
-        self.w.push_str("\"");
+        self.w.push('"');
     }
 
     fn escaped_attr(&mut self, value: &str) {
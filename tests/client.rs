@@ -0,0 +1,386 @@
+use dbus_launch::client::{Connection, MethodError, Value};
+use dbus_launch::{Auth, DaemonType, Launcher};
+use std::io::ErrorKind;
+use std::process::Command;
+
+fn call(conn: &mut Connection, member: &str, args: &[Value]) -> Vec<Value> {
+    conn.call(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        "org.freedesktop.DBus",
+        member,
+        args,
+    )
+    .unwrap()
+}
+
+fn has_dbus_broker() -> bool {
+    let ok = Command::new("dbus-broker")
+        .arg("--version")
+        .output()
+        .is_ok();
+    if !ok {
+        println!("test ignored: dbus-broker --version failed");
+    }
+    ok
+}
+
+/// Queries the bus over a connection established using EXTERNAL mechanism.
+fn connect(daemon_type: DaemonType) {
+    let daemon = Launcher::new(daemon_type)
+        .service("com.test.A", "/usr/bin/false")
+        .launch()
+        .unwrap();
+    let mut conn = daemon.connect().unwrap();
+    assert!(conn.unique_name().starts_with(':'));
+
+    let names = call(&mut conn, "ListNames", &[]);
+    let own = Value::String(conn.unique_name().to_owned());
+    match names.as_slice() {
+        [Value::Array(element, names)] => {
+            assert_eq!("s", element);
+            assert!(names.contains(&own), "{:?}", names);
+        }
+        _ => panic!("unexpected reply {:?}", names),
+    }
+
+    let activatable = call(&mut conn, "ListActivatableNames", &[]);
+    assert!(format!("{:?}", activatable).contains("com.test.A"));
+
+    let has_owner = call(&mut conn, "NameHasOwner", &[own]);
+    assert_eq!(vec![Value::Boolean(true)], has_owner);
+
+    let error = conn
+        .call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "GetNameOwner",
+            &[Value::String("com.test.A".into())],
+        )
+        .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .starts_with("org.freedesktop.DBus.Error.NameHasNoOwner"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn connect_dbus() {
+    connect(DaemonType::DBusDaemon);
+}
+
+#[test]
+fn connect_broker() {
+    if has_dbus_broker() {
+        connect(DaemonType::DBusBroker);
+    }
+}
+
+#[test]
+fn connect_broker_direct() {
+    if has_dbus_broker() {
+        connect(DaemonType::DBusBrokerDirect);
+    }
+}
+
+/// ANONYMOUS mechanism is used over TCP when allowed.
+#[test]
+fn connect_tcp_anonymous() {
+    let daemon = Launcher::daemon()
+        .listen("tcp:host=localhost")
+        .auth(Auth::Anonymous)
+        .allow_anonymous()
+        .launch()
+        .unwrap();
+    let mut conn = daemon.connect().unwrap();
+    let id = call(&mut conn, "GetId", &[]);
+    assert_eq!(32, id[0].as_str().unwrap().len());
+}
+
+/// Authentication fails when no supported mechanism is allowed.
+#[test]
+fn connect_rejected() {
+    // EXTERNAL requires credentials unavailable over TCP.
+    let daemon = Launcher::daemon()
+        .listen("tcp:host=localhost")
+        .auth(Auth::External)
+        .launch()
+        .unwrap();
+    let error = daemon.connect().unwrap_err();
+    assert_eq!(ErrorKind::PermissionDenied, error.kind());
+}
+
+/// The next address is tried when authentication fails on the previous one.
+#[test]
+fn connect_fallback() {
+    let rejecting = Launcher::daemon()
+        .listen("tcp:host=localhost")
+        .auth(Auth::External)
+        .launch()
+        .unwrap();
+    let daemon = Launcher::daemon().launch().unwrap();
+    let address = format!("{};{}", rejecting.address(), daemon.address());
+    let mut conn = Connection::open(&address).unwrap();
+    call(&mut conn, "GetId", &[]);
+}
+
+/// Arguments that cannot be serialized are rejected without affecting the
+/// connection.
+#[test]
+fn invalid_args() {
+    let daemon = Launcher::daemon().launch().unwrap();
+    let mut conn = daemon.connect().unwrap();
+    for args in &[
+        [Value::Array(String::new(), vec![])],
+        [Value::Array("s".into(), vec![Value::Uint32(1)])],
+    ] {
+        let error = conn
+            .call(
+                "org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "ListNames",
+                args,
+            )
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+    }
+    call(&mut conn, "ListNames", &[]);
+}
+
+fn kind<T>(result: std::io::Result<T>) -> ErrorKind {
+    result.map(drop).unwrap_err().kind()
+}
+
+/// Invalid bus names are rejected before anything is sent to the bus.
+#[test]
+fn invalid_names() {
+    let timeout = std::time::Duration::from_millis(100);
+    let daemon = Launcher::daemon().launch().unwrap();
+    let mut events = daemon.name_events().unwrap();
+    let mut conn = daemon.connect().unwrap();
+    for name in &["", "com", "com..test", ":1.1"] {
+        let path = "/org/freedesktop/DBus";
+        let call = conn.call("org.freedesktop.DBus", path, name, "ListNames", &[]);
+        assert_eq!(ErrorKind::InvalidInput, kind(call));
+        if !name.starts_with(':') {
+            let call = conn.call(name, path, "org.freedesktop.DBus", "ListNames", &[]);
+            assert_eq!(ErrorKind::InvalidInput, kind(call));
+            assert_eq!(ErrorKind::InvalidInput, kind(daemon.name_owner(name)));
+            assert_eq!(
+                ErrorKind::InvalidInput,
+                kind(daemon.connection_credentials(name))
+            );
+            assert_eq!(
+                ErrorKind::InvalidInput,
+                kind(events.wait_for_owner(name, timeout))
+            );
+            assert_eq!(
+                ErrorKind::InvalidInput,
+                kind(events.wait_for_release(name, timeout))
+            );
+        }
+        assert_eq!(
+            ErrorKind::InvalidInput,
+            kind(daemon.start_service(name, timeout))
+        );
+    }
+}
+
+/// Calls time out when the reply does not arrive in time.
+#[test]
+fn timeout() {
+    let daemon = Launcher::daemon().launch().unwrap();
+    let mut conn = daemon.connect().unwrap();
+    conn.set_timeout(Some(std::time::Duration::from_millis(50)))
+        .unwrap();
+    // The bus waits for the reply from the connection itself.
+    let name = conn.unique_name().to_owned();
+    let error = conn
+        .call(&name, "/", "com.test.Interface", "Method", &[])
+        .unwrap_err();
+    assert_eq!(ErrorKind::TimedOut, error.kind());
+}
+
+/// Bus state is available through typed queries.
+fn query(daemon_type: DaemonType) {
+    let daemon = Launcher::new(daemon_type)
+        .service("com.test.A", "/usr/bin/false")
+        .launch()
+        .unwrap();
+
+    let names = daemon.list_names().unwrap();
+    let names: Vec<_> = names.iter().map(|name| name.as_str()).collect();
+    assert_eq!(vec!["org.freedesktop.DBus"], names);
+
+    let activatable = daemon.list_activatable_names().unwrap();
+    assert!(
+        activatable.iter().any(|name| name.as_str() == "com.test.A"),
+        "{:?}",
+        activatable
+    );
+
+    let conn = daemon.connect().unwrap();
+    let owner = daemon.name_owner(conn.unique_name()).unwrap().unwrap();
+    assert_eq!(conn.unique_name(), owner.as_str());
+    assert_eq!(None, daemon.name_owner("com.test.A").unwrap());
+    let names = daemon.list_names().unwrap();
+    assert!(names.contains(&owner), "{:?}", names);
+
+    let credentials = daemon.connection_credentials(&owner).unwrap();
+    assert_eq!(Some(unsafe { libc::getuid() }), credentials.unix_user_id());
+    assert_eq!(Some(std::process::id()), credentials.process_id());
+
+    let error = daemon.connection_credentials("com.test.A").unwrap_err();
+    let error = MethodError::from_io(&error).unwrap();
+    assert_eq!("org.freedesktop.DBus.Error.NameHasNoOwner", error.name());
+
+    daemon.features().unwrap();
+}
+
+#[test]
+fn query_dbus() {
+    query(DaemonType::DBusDaemon);
+}
+
+#[test]
+fn query_broker() {
+    if has_dbus_broker() {
+        query(DaemonType::DBusBroker);
+    }
+}
+
+#[test]
+fn query_broker_direct() {
+    if has_dbus_broker() {
+        query(DaemonType::DBusBrokerDirect);
+    }
+}
+
+/// Changes of name owners are observed in order.
+fn name_events(daemon_type: DaemonType) {
+    let timeout = std::time::Duration::from_secs(10);
+    let daemon = Launcher::new(daemon_type).launch().unwrap();
+    let mut events = daemon.name_events().unwrap();
+
+    let mut conn = daemon.connect().unwrap();
+    let unique_name = conn.unique_name().to_owned();
+    let event = events.recv_timeout(timeout).unwrap();
+    assert_eq!(unique_name, event.name().as_str());
+    assert_eq!(None, event.old_owner());
+    assert_eq!(
+        Some(unique_name.as_str()),
+        event.new_owner().map(|n| n.as_str())
+    );
+
+    let name = Value::String("com.test.Name".into());
+    call(&mut conn, "RequestName", &[name, Value::Uint32(0)]);
+    let owner = events.wait_for_owner("com.test.Name", timeout).unwrap();
+    assert_eq!(unique_name, owner.as_str());
+
+    drop(conn);
+    let owner = events.wait_for_release("com.test.Name", timeout).unwrap();
+    assert_eq!(unique_name, owner.as_str());
+    let event = events.wait_for(timeout, |e| e.name().is_unique()).unwrap();
+    assert_eq!(unique_name, event.name().as_str());
+    assert_eq!(None, event.new_owner());
+
+    let error = events
+        .recv_timeout(std::time::Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(ErrorKind::TimedOut, error.kind());
+
+    drop(daemon);
+    assert!(events.next().is_none());
+}
+
+#[test]
+fn name_events_dbus() {
+    name_events(DaemonType::DBusDaemon);
+}
+
+#[test]
+fn name_events_broker() {
+    if has_dbus_broker() {
+        name_events(DaemonType::DBusBroker);
+    }
+}
+
+#[test]
+fn name_events_broker_direct() {
+    if has_dbus_broker() {
+        name_events(DaemonType::DBusBrokerDirect);
+    }
+}
+
+/// Statistics include match rules and names of connections.
+#[test]
+fn stats() {
+    let daemon = Launcher::daemon().allow_stats().launch().unwrap();
+    let mut conn = daemon.connect().unwrap();
+    let rule = "type='signal',interface='com.test.Interface'";
+    call(&mut conn, "AddMatch", &[Value::String(rule.into())]);
+    let name = Value::String("com.test.Name".into());
+    call(&mut conn, "RequestName", &[name, Value::Uint32(0)]);
+
+    let stats = match daemon.stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            println!("test ignored: statistics not supported: {}", e);
+            return;
+        }
+    };
+    assert_eq!(1, stats.connections().len(), "{:?}", stats);
+    let connection = stats.connection(conn.unique_name()).unwrap();
+    assert_eq!(&[rule.to_owned()], connection.match_rules());
+    assert_eq!(1, connection.peak_match_rules());
+    assert_eq!(2, connection.bus_names());
+    assert_eq!(2, stats.active_connections());
+    assert_eq!(1, stats.match_rules());
+    assert_eq!(3, stats.bus_names());
+
+    call(&mut conn, "RemoveMatch", &[Value::String(rule.into())]);
+    let stats = daemon.stats().unwrap();
+    let connection = stats.connection(conn.unique_name()).unwrap();
+    assert!(connection.match_rules().is_empty());
+    assert_eq!(1, connection.peak_match_rules());
+    assert_eq!(0, stats.match_rules());
+}
+
+/// Connections and match rules added after launch are reported as leaks.
+#[test]
+fn check_leaks() {
+    let rule = "type='signal',interface='com.test.Interface'";
+
+    let daemon = Launcher::daemon().check_leaks().launch().unwrap();
+    let mut conn = daemon.connect().unwrap();
+    call(&mut conn, "AddMatch", &[Value::String(rule.into())]);
+    call(&mut conn, "RemoveMatch", &[Value::String(rule.into())]);
+    drop(conn);
+    daemon.stop().unwrap();
+
+    let daemon = Launcher::daemon().check_leaks().launch().unwrap();
+    let mut conn = daemon.connect().unwrap();
+    call(&mut conn, "AddMatch", &[Value::String(rule.into())]);
+    let error = daemon.stop().unwrap_err();
+    let leaks = dbus_launch::Leaks::from_io(&error).unwrap();
+    assert_eq!(conn.unique_name(), leaks.connections()[0].as_str());
+    if !leaks.match_rules().is_empty() {
+        assert_eq!(conn.unique_name(), leaks.match_rules()[0].0.as_str());
+        assert_eq!(rule, leaks.match_rules()[0].1);
+    }
+
+    let result = std::thread::spawn(|| {
+        let daemon = Launcher::daemon().check_leaks().launch().unwrap();
+        let _conn = daemon.connect().unwrap();
+        drop(daemon);
+    })
+    .join();
+    let panic = result.unwrap_err();
+    let message = panic.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("leaked 1 connection(s)"), "{}", message);
+}
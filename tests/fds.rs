@@ -50,22 +50,31 @@ fn launch_parallel(n: usize) {
 }
 
 fn child() {
-    assert_eq!(4, count_open_fds());
+    assert_eq!(5, count_open_fds());
 
-    let mut fd = None;
+    let mut address_fd = None;
+    let mut pid_fd = None;
     for arg in std::env::args() {
         if let Some(n) = arg.strip_prefix("--print-address=") {
             let n = n.parse::<c_int>().unwrap();
             assert_eq!(n, 3);
-            fd = Some(n);
+            address_fd = Some(n);
+        }
+        if let Some(n) = arg.strip_prefix("--print-pid=") {
+            let n = n.parse::<c_int>().unwrap();
+            assert_eq!(n, 4);
+            pid_fd = Some(n);
         }
     }
 
-    let fd = fd.unwrap();
+    write_and_close(address_fd.unwrap(), "everything-ok");
+    write_and_close(pid_fd.unwrap(), &std::process::id().to_string());
+}
+
+fn write_and_close(fd: c_int, s: &str) {
     unsafe {
-        let address = "everything-ok";
-        let n = libc::write(fd, address.as_ptr().cast(), address.len());
-        assert_eq!(n, address.len() as isize);
+        let n = libc::write(fd, s.as_ptr().cast(), s.len());
+        assert_eq!(n, s.len() as isize);
         let n = libc::close(fd);
         assert_eq!(n, 0);
     };
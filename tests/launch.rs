@@ -1,6 +1,7 @@
-use dbus_launch::{DaemonType, Launcher};
+use dbus_launch::{DaemonType, Launcher, Resource};
 use std::ffi::OsStr;
-use std::process::{Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 /// Unix transport is used by default.
 #[test]
@@ -41,22 +42,12 @@ fn service_support(daemon_type: DaemonType) {
     let daemon = launch.launch().unwrap();
 
     // Obtain the list of activatable names.
-    let address = format!("--bus={}", daemon.address());
-    let activatable = check_output(
-        &"dbus-send",
-        &[
-            &address,
-            "--print-reply",
-            "--dest=org.freedesktop.DBus",
-            "/org/freedesktop/DBus",
-            "org.freedesktop.DBus.ListActivatableNames",
-        ],
-    );
+    let activatable = daemon.list_activatable_names().unwrap();
 
     for service in services {
         assert!(
-            activatable.contains(service),
-            "Service {} should be among activatable names: {}",
+            activatable.iter().any(|name| name.as_str() == *service),
+            "Service {} should be among activatable names: {:?}",
             service,
             activatable,
         );
@@ -70,24 +61,591 @@ fn service_support_dbus() {
 
 #[test]
 fn service_support_broker() {
-    if let Ok(_) = Command::new("dbus-broker").arg("--version").output() {
+    if has_dbus_broker() {
         service_support(DaemonType::DBusBroker);
-    } else {
-        println!("test ignored: dbus-broker --version failed")
-    }
-}
-
-fn check_output<I, S>(program: S, args: I) -> String
-where
-    I: IntoIterator<Item = S>,
-    S: AsRef<OsStr>,
-{
-    let child = Command::new(program)
-        .args(args)
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("failed to execute child");
-    let output = child.wait_with_output().expect("failed to wait on child");
-    assert!(output.status.success(), "child process failed");
-    String::from_utf8(output.stdout).expect("child output is not valid utf-8")
+    }
+}
+
+#[test]
+fn service_support_broker_direct() {
+    if has_dbus_broker() {
+        service_support(DaemonType::DBusBrokerDirect);
+    }
+}
+
+/// The PID of the broker itself is reported when started directly.
+#[test]
+fn broker_direct_pid() {
+    if !has_dbus_broker() {
+        return;
+    }
+    let daemon = Launcher::new(DaemonType::DBusBrokerDirect)
+        .launch()
+        .unwrap();
+    let exe = std::fs::read_link(format!("/proc/{}/exe", daemon.pid())).unwrap();
+    assert!(exe.ends_with("dbus-broker"), "{}", exe.display());
+}
+
+/// Broker listens on all requested Unix domain sockets.
+#[test]
+fn listen_broker_multiple() {
+    if !has_dbus_broker() {
+        return;
+    }
+    for daemon_type in &[DaemonType::DBusBroker, DaemonType::DBusBrokerDirect] {
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = Launcher::new(*daemon_type)
+            .listen(&format!("unix:path={}/a", dir.path().display()))
+            .listen(&format!("unix:dir={}", dir.path().display()))
+            .listen("unix:abstract=")
+            .launch()
+            .unwrap();
+        assert_eq!(3, daemon.address().split(';').count());
+        assert!(dir.path().join("a").exists());
+    }
+}
+
+/// Transports unsupported by the broker are reported as errors.
+#[test]
+fn listen_broker_tcp() {
+    let error = Launcher::broker()
+        .listen("tcp:host=localhost")
+        .launch()
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::Unsupported, error.kind());
+}
+
+/// Early exit of dbus-broker-launch is reported as a launch error.
+#[test]
+fn broker_early_exit() {
+    let error = Launcher::broker()
+        .program(OsStr::new("false"))
+        .launch()
+        .unwrap_err();
+    assert!(error.to_string().contains("exited"), "{}", error);
+}
+
+/// Sandboxed daemon is reachable, but runs in a separate network namespace.
+#[cfg(target_os = "linux")]
+#[test]
+fn sandbox() {
+    use dbus_launch::Sandbox;
+
+    let daemon = Launcher::daemon()
+        .sandbox(Sandbox::new())
+        .service("com.test.A", "/usr/bin/false")
+        .launch()
+        .unwrap();
+
+    let activatable = daemon.list_activatable_names().unwrap();
+    assert!(
+        activatable.iter().any(|name| name.as_str() == "com.test.A"),
+        "{:?}",
+        activatable
+    );
+
+    let ns = |pid: &str| std::fs::read_link(format!("/proc/{}/ns/net", pid)).unwrap();
+    assert_ne!(ns("self"), ns(&daemon.pid().to_string()));
+
+    let mut devices: Vec<_> =
+        std::fs::read_dir(format!("/proc/{}/root/dev", daemon.bus_pid()))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+    devices.sort();
+    assert_eq!(vec!["null", "urandom", "zero"], devices);
+}
+
+/// Daemon runs as requested user and remains reachable by the launching user.
+#[test]
+fn uid() {
+    if unsafe { libc::geteuid() } != 0 {
+        println!("test ignored: requires root");
+        return;
+    }
+    let daemon = Launcher::daemon().uid(65534).gid(65534).launch().unwrap();
+
+    let status =
+        std::fs::read_to_string(format!("/proc/{}/status", daemon.pid())).unwrap();
+    assert!(
+        status.contains("\nUid:\t65534\t65534\t65534\t65534\n"),
+        "{}",
+        status
+    );
+    assert!(
+        status.contains("\nGid:\t65534\t65534\t65534\t65534\n"),
+        "{}",
+        status
+    );
+    assert!(
+        status.lines().any(|line| line.trim() == "Groups:"),
+        "{}",
+        status
+    );
+
+    daemon.list_names().unwrap();
+}
+
+/// dbus-daemon requires its user to have an entry in the user database, so
+/// the launch fails early without one.
+#[test]
+fn uid_unmapped() {
+    let uid = 12345;
+    if !unsafe { libc::getpwuid(uid) }.is_null() {
+        println!("test ignored: uid {} has a user database entry", uid);
+        return;
+    }
+    let err = Launcher::daemon().uid(uid).gid(uid).launch().unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+    assert!(err.to_string().contains("user database"), "{}", err);
+}
+
+#[test]
+fn uid_broker() {
+    if unsafe { libc::geteuid() } != 0 {
+        println!("test ignored: requires root");
+        return;
+    }
+    if !has_dbus_broker() {
+        return;
+    }
+    let daemon = Launcher::broker().uid(65534).gid(65534).launch().unwrap();
+    daemon.list_names().unwrap();
+}
+
+/// Working directory, umask and resource limits are applied to the daemon.
+#[test]
+fn process_attributes() {
+    let daemon = Launcher::daemon()
+        .current_dir("/")
+        .umask(0o027)
+        .rlimit(Resource::Core, 0, 4096)
+        .launch()
+        .unwrap();
+
+    let proc = format!("/proc/{}", daemon.pid());
+    assert_eq!(
+        Path::new("/"),
+        std::fs::read_link(format!("{}/cwd", proc)).unwrap()
+    );
+    let status = std::fs::read_to_string(format!("{}/status", proc)).unwrap();
+    assert!(status.contains("\nUmask:\t0027\n"), "{}", status);
+    let limits = std::fs::read_to_string(format!("{}/limits", proc)).unwrap();
+    let core = limits
+        .lines()
+        .find(|line| line.starts_with("Max core file size"))
+        .unwrap();
+    assert_eq!(
+        vec!["0", "4096", "bytes"],
+        core.split_whitespace().skip(4).collect::<Vec<_>>()
+    );
+}
+
+/// Additional file descriptors are passed to the daemon.
+#[test]
+fn inherit_fd() {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    // Second launch uses fork instead of posix_spawn due to umask.
+    for &fork in &[false, true] {
+        let (_ours, a) = UnixStream::pair().unwrap();
+        let (_ours, b) = UnixStream::pair().unwrap();
+        let link =
+            |pid: &str, fd| std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd));
+        let a_link = link("self", a.as_raw_fd()).unwrap();
+        let b_link = link("self", b.as_raw_fd()).unwrap();
+
+        let mut launcher = Launcher::daemon();
+        launcher.inherit_fd(9, a).inherit_fd(7, b);
+        if fork {
+            launcher.umask(0o022);
+        }
+        let daemon = launcher.launch().unwrap();
+
+        let pid = daemon.pid().to_string();
+        assert_eq!(a_link, link(&pid, 9).unwrap());
+        assert_eq!(b_link, link(&pid, 7).unwrap());
+    }
+}
+
+/// Pre-bound sockets are passed using socket activation.
+fn listen_socket(daemon_type: DaemonType) {
+    use std::os::unix::net::UnixListener;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("socket");
+    let listener = UnixListener::bind(&path).unwrap();
+    let daemon = Launcher::new(daemon_type)
+        .listen_socket(listener)
+        .launch()
+        .unwrap();
+    assert!(
+        daemon.address().contains(path.to_str().unwrap()),
+        "{}",
+        daemon.address()
+    );
+
+    let address = format!("unix:path={}", path.display());
+    let mut conn = dbus_launch::client::Connection::open(&address).unwrap();
+    conn.list_names().unwrap();
+}
+
+#[test]
+fn listen_socket_dbus() {
+    if !dbus_daemon_supports_systemd() {
+        println!("test ignored: dbus-daemon compiled without systemd support");
+        return;
+    }
+    listen_socket(DaemonType::DBusDaemon);
+}
+
+#[test]
+fn listen_socket_broker() {
+    if !has_dbus_broker() {
+        return;
+    }
+    listen_socket(DaemonType::DBusBroker);
+    listen_socket(DaemonType::DBusBrokerDirect);
+}
+
+/// Any socket supported by dbus-daemon can be passed.
+#[test]
+fn listen_fd_tcp() {
+    if !dbus_daemon_supports_systemd() {
+        println!("test ignored: dbus-daemon compiled without systemd support");
+        return;
+    }
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let daemon = Launcher::daemon().listen_fd(listener).launch().unwrap();
+    assert!(
+        daemon.address().contains(&format!("port={}", port)),
+        "{}",
+        daemon.address()
+    );
+}
+
+fn has_dbus_broker() -> bool {
+    let ok = Command::new("dbus-broker")
+        .arg("--version")
+        .output()
+        .is_ok();
+    if !ok {
+        println!("test ignored: dbus-broker --version failed");
+    }
+    ok
+}
+
+fn dbus_daemon_supports_systemd() -> bool {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("daemon.conf");
+    std::fs::write(&config, "<busconfig><listen>systemd:</listen></busconfig>").unwrap();
+    let output = Command::new("dbus-daemon")
+        .arg("--nofork")
+        .arg("--config-file")
+        .arg(&config)
+        .env_remove("LISTEN_FDS")
+        .output()
+        .unwrap();
+    !String::from_utf8_lossy(&output.stderr).contains("without systemd support")
+}
+
+fn comm(pid: i32) -> String {
+    std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .unwrap()
+        .trim()
+        .to_owned()
+}
+
+/// The PID of the process serving connections is available.
+#[test]
+fn bus_pid_dbus() {
+    let daemon = Launcher::daemon().launch().unwrap();
+    assert_eq!(daemon.pid(), daemon.bus_pid());
+}
+
+#[test]
+fn bus_pid_broker() {
+    if !has_dbus_broker() {
+        return;
+    }
+    let daemon = Launcher::broker().launch().unwrap();
+    assert_ne!(daemon.pid(), daemon.bus_pid());
+    assert_eq!("dbus-broker", comm(daemon.bus_pid()));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn bus_pid_sandbox() {
+    let daemon = Launcher::daemon()
+        .sandbox(dbus_launch::Sandbox::new())
+        .launch()
+        .unwrap();
+    assert_ne!(daemon.pid(), daemon.bus_pid());
+    assert_eq!("dbus-daemon", comm(daemon.bus_pid()));
+    assert_eq!(
+        Vec::<dbus_launch::ProcessInfo>::new(),
+        daemon.services().unwrap()
+    );
+}
+
+/// Activated services are stopped together with the daemon.
+fn services_stopped(daemon_type: DaemonType) {
+    let dir = tempfile::tempdir().unwrap();
+    let exec = script_service(dir.path(), "service", "exec sleep 1000\n");
+
+    let daemon = Launcher::new(daemon_type)
+        .service("com.test.Sleep", &exec)
+        .launch()
+        .unwrap();
+    assert_eq!(
+        Vec::<dbus_launch::ProcessInfo>::new(),
+        daemon.services().unwrap()
+    );
+
+    // Activation never completes, so the call times out.
+    Command::new("dbus-send")
+        .arg(format!("--bus={}", daemon.address()))
+        .args([
+            "--print-reply",
+            "--reply-timeout=100",
+            "--dest=org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus.StartServiceByName",
+            "string:com.test.Sleep",
+            "uint32:0",
+        ])
+        .output()
+        .unwrap();
+    let start = std::time::Instant::now();
+    while daemon.services().unwrap().is_empty() {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let services = daemon.stop().unwrap();
+    assert_eq!(1, services.len(), "{:?}", services);
+    assert_eq!("sleep", services[0].name());
+    let pid = services[0].pid();
+    let start = std::time::Instant::now();
+    loop {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid));
+        match stat {
+            Ok(stat) if !stat.contains(") Z ") => {}
+            _ => break,
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn services_stopped_dbus() {
+    services_stopped(DaemonType::DBusDaemon);
+}
+
+#[test]
+fn services_stopped_broker_direct() {
+    if !has_dbus_broker() {
+        return;
+    }
+    services_stopped(DaemonType::DBusBrokerDirect);
+}
+
+/// Service activations are recorded together with their exit codes.
+fn activations(daemon_type: DaemonType) {
+    let dir = tempfile::tempdir().unwrap();
+    let exec = script_service(dir.path(), "service", "exit 3\n");
+
+    let daemon = Launcher::new(daemon_type)
+        .service("com.test.Exit", &exec)
+        .launch()
+        .unwrap();
+    assert!(daemon.activations().unwrap().is_empty());
+
+    // The service exits without acquiring the name, so the call fails.
+    Command::new("dbus-send")
+        .arg(format!("--bus={}", daemon.address()))
+        .args([
+            "--print-reply",
+            "--dest=org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus.StartServiceByName",
+            "string:com.test.Exit",
+            "uint32:0",
+        ])
+        .output()
+        .unwrap();
+    let start = std::time::Instant::now();
+    let activations = loop {
+        let activations = daemon.activations().unwrap();
+        if activations.iter().all(|a| a.exit_code().is_some()) && !activations.is_empty()
+        {
+            break activations;
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+
+    assert_eq!(1, activations.len(), "{:?}", activations);
+    assert_eq!("com.test.Exit", activations[0].name());
+    assert_eq!(&[exec.as_os_str()], activations[0].args());
+    assert_eq!(Some(3), activations[0].exit_code());
+    assert!(activations[0].pid() > 0);
+    assert!(activations[0].started() <= std::time::SystemTime::now());
+}
+
+#[test]
+fn activations_dbus() {
+    activations(DaemonType::DBusDaemon);
+}
+
+#[test]
+fn activations_broker_direct() {
+    if !has_dbus_broker() {
+        return;
+    }
+    activations(DaemonType::DBusBrokerDirect);
+}
+
+/// Failed activation is reported with the error from the bus.
+#[test]
+fn start_service_failed() {
+    use dbus_launch::client::StartReply;
+
+    let dir = tempfile::tempdir().unwrap();
+    let exec = script_service(dir.path(), "service", "exit 3\n");
+
+    let daemon = Launcher::daemon()
+        .service("com.test.Exit", &exec)
+        .launch()
+        .unwrap();
+    let timeout = std::time::Duration::from_secs(10);
+
+    match daemon.start_service("com.test.Unknown", timeout).unwrap() {
+        StartReply::Failed(error) => {
+            assert_eq!("org.freedesktop.DBus.Error.ServiceUnknown", error.name())
+        }
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match daemon.start_service("com.test.Exit", timeout).unwrap() {
+        StartReply::Failed(error) => {
+            assert_eq!("org.freedesktop.DBus.Error.Spawn.ChildExited", error.name());
+            assert!(error.message().unwrap().contains('3'), "{:?}", error);
+        }
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+}
+
+/// Writes an executable shell script with given body to be used as a service.
+fn script_service(dir: &Path, name: &str, body: &str) -> PathBuf {
+    let exec = dir.join(name);
+    std::fs::write(&exec, format!("#!/bin/sh\n{}", body)).unwrap();
+    std::fs::set_permissions(&exec, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+        .unwrap();
+    exec
+}
+
+/// Parses a pcap file into a list of captured messages.
+fn read_pcap(path: &Path) -> Vec<Vec<u8>> {
+    let pcap = std::fs::read(path).unwrap();
+    assert_eq!(b"\xd4\xc3\xb2\xa1", &pcap[..4]);
+    assert_eq!(231u32.to_le_bytes(), pcap[20..24]);
+    let mut messages = Vec::new();
+    let mut rest = &pcap[24..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+        messages.push(rest[16..16 + len].to_vec());
+        rest = &rest[16 + len..];
+    }
+    messages
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Bus traffic is captured into a pcap file.
+fn monitor(daemon_type: DaemonType) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bus.pcap");
+    let daemon = Launcher::new(daemon_type).monitor(&path).launch().unwrap();
+    daemon.list_activatable_names().unwrap();
+    drop(daemon);
+
+    let messages = read_pcap(&path);
+    assert!(
+        messages
+            .iter()
+            .any(|m| contains(m, b"ListActivatableNames")),
+        "{:?}",
+        messages
+    );
+}
+
+#[test]
+fn monitor_dbus() {
+    monitor(DaemonType::DBusDaemon);
+}
+
+#[test]
+fn monitor_broker() {
+    if !has_dbus_broker() {
+        return;
+    }
+    monitor(DaemonType::DBusBroker);
+}
+
+/// Capture started on a running daemon is retained when a test fails.
+#[test]
+fn start_monitor() {
+    let mut daemon = Launcher::daemon().launch().unwrap();
+    let path = daemon.start_monitor().unwrap();
+    daemon.list_names().unwrap();
+    drop(daemon);
+    assert!(!path.exists());
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let result = std::thread::spawn(move || {
+        let mut daemon = Launcher::daemon().launch().unwrap();
+        sender.send(daemon.start_monitor().unwrap()).unwrap();
+        daemon.list_names().unwrap();
+        panic!("test failure");
+    })
+    .join();
+    assert!(result.is_err());
+    let path = receiver.recv().unwrap();
+    let messages = read_pcap(&path);
+    assert!(messages.iter().any(|m| contains(m, b"ListNames")));
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+/// Recent messages are available as text while the daemon is running.
+#[test]
+fn trace() {
+    let mut daemon = Launcher::daemon().launch().unwrap();
+    assert_eq!(None, daemon.trace());
+    daemon.start_monitor().unwrap();
+    daemon.name_owner("com.example.Missing").unwrap();
+
+    let start = std::time::Instant::now();
+    loop {
+        let trace = daemon.trace().unwrap();
+        if trace.contains("error_name=org.freedesktop.DBus.Error.NameHasNoOwner") {
+            assert!(trace.contains("member=GetNameOwner"), "{}", trace);
+            assert!(
+                trace.contains("   string \"com.example.Missing\"\n"),
+                "{}",
+                trace
+            );
+            break;
+        }
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(10),
+            "{}",
+            trace
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
 }
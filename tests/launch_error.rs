@@ -8,4 +8,9 @@ fn main() {
 
     let error = dbus_launch::Launcher::broker().launch().unwrap_err();
     assert_eq!(std::io::ErrorKind::NotFound, error.kind());
+
+    let error = dbus_launch::Launcher::new(dbus_launch::DaemonType::DBusBrokerDirect)
+        .launch()
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::NotFound, error.kind());
 }
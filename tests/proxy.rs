@@ -0,0 +1,108 @@
+use dbus_launch::client::{Connection, Value};
+use dbus_launch::{FaultConfig, Launcher};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Traffic is forwarded unchanged without faults, and with chunked and
+/// delayed writes.
+#[test]
+fn transparent() {
+    let mut daemon = Launcher::daemon().launch().unwrap();
+    let plain = daemon.proxy(&FaultConfig::new(1)).unwrap();
+    let slow = daemon
+        .proxy(
+            FaultConfig::new(2)
+                .chunk_size(7)
+                .latency(Duration::ZERO, Duration::from_micros(100)),
+        )
+        .unwrap();
+    assert_ne!(plain, slow);
+
+    for address in &[plain, slow] {
+        let mut conn = Connection::open(address).unwrap();
+        let unique_name = conn.unique_name().to_owned();
+        let names = conn.list_names().unwrap();
+        assert!(names.iter().any(|name| name.as_str() == unique_name));
+    }
+}
+
+/// The bus sees credentials of the proxy rather than those of the client,
+/// both in GetConnectionCredentials and in the older per-field queries.
+#[test]
+fn credentials() {
+    let mut daemon = Launcher::daemon().launch().unwrap();
+    let address = daemon.proxy(&FaultConfig::new(1)).unwrap();
+    let mut events = daemon.name_events().unwrap();
+
+    // The call is never answered, so the client remains connected until
+    // killed.
+    let target = daemon.connect().unwrap();
+    let mut child = Command::new("dbus-send")
+        .arg(format!("--bus={}", address))
+        .arg(format!("--dest={}", target.unique_name()))
+        .args([
+            "--print-reply",
+            "--reply-timeout=60000",
+            "/",
+            "com.test.Call",
+        ])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let timeout = Duration::from_secs(10);
+    let event = events
+        .wait_for(timeout, |event| {
+            event.name().is_unique()
+                && event.old_owner().is_none()
+                && event.name().as_str() != target.unique_name()
+        })
+        .unwrap();
+    let credentials = daemon.connection_credentials(event.name()).unwrap();
+    assert_eq!(Some(std::process::id()), credentials.process_id());
+    assert_ne!(Some(child.id()), credentials.process_id());
+    assert_eq!(Some(unsafe { libc::getuid() }), credentials.unix_user_id());
+
+    let mut conn = daemon.connect().unwrap();
+    let name = Value::String(event.name().as_str().to_owned());
+    let mut call = |member| {
+        conn.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            member,
+            std::slice::from_ref(&name),
+        )
+        .unwrap()
+    };
+    assert_eq!(
+        vec![Value::Uint32(std::process::id())],
+        call("GetConnectionUnixProcessID")
+    );
+    assert_eq!(
+        vec![Value::Uint32(unsafe { libc::getuid() })],
+        call("GetConnectionUnixUser")
+    );
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+/// Connections are closed once the limit is reached.
+#[test]
+fn drop_after() {
+    let mut daemon = Launcher::daemon().launch().unwrap();
+
+    let address = daemon
+        .proxy(FaultConfig::new(1).drop_after_bytes(10))
+        .unwrap();
+    assert!(Connection::open(&address).is_err());
+
+    // Hello and its reply get through, but the connection is closed after
+    // the NameAcquired signal that follows.
+    let address = daemon
+        .proxy(FaultConfig::new(1).drop_after_messages(2))
+        .unwrap();
+    let mut conn = Connection::open(&address).unwrap();
+    assert!(conn.list_names().is_err());
+}
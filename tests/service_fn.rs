@@ -0,0 +1,129 @@
+//! Verifies that services implemented as functions are activated in a new
+//! instance of the test executable.
+
+use dbus_launch::client::{Connection, StartReply, Value};
+use dbus_launch::{DaemonType, Launcher};
+use std::io::ErrorKind;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+fn main() {
+    dbus_launch::service_main(&[
+        ("com.test.A", service_a),
+        ("com.test.B", service_b),
+        ("com.test.Name", service_name),
+    ]);
+
+    unregistered();
+
+    activate(DaemonType::DBusDaemon);
+    start(DaemonType::DBusDaemon);
+    if has_dbus_broker() {
+        activate(DaemonType::DBusBrokerDirect);
+        start(DaemonType::DBusBrokerDirect);
+    }
+}
+
+fn has_dbus_broker() -> bool {
+    Command::new("dbus-broker")
+        .arg("--version")
+        .output()
+        .is_ok()
+}
+
+fn service_a() {
+    assert!(std::env::var_os("DBUS_STARTER_ADDRESS").is_some());
+    std::process::exit(11);
+}
+
+fn service_b() {
+    std::process::exit(12);
+}
+
+/// Acquires its name and waits until stopped together with the daemon.
+fn service_name() {
+    let address = std::env::var("DBUS_STARTER_ADDRESS").unwrap();
+    let mut conn = Connection::open(&address).unwrap();
+    let reply = conn
+        .call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "RequestName",
+            &[Value::String("com.test.Name".into()), Value::Uint32(0)],
+        )
+        .unwrap();
+    // DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    assert_eq!(vec![Value::Uint32(1)], reply);
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Services report back to the launching process by acquiring a name.
+/// Running services are not reported as leaks.
+fn start(daemon_type: DaemonType) {
+    let daemon = Launcher::new(daemon_type)
+        .service_fn("com.test.Name")
+        .check_leaks()
+        .launch()
+        .unwrap();
+    let timeout = Duration::from_secs(10);
+    assert_eq!(
+        StartReply::Started,
+        daemon.start_service("com.test.Name", timeout).unwrap()
+    );
+    assert_eq!(
+        StartReply::AlreadyRunning,
+        daemon.start_service("com.test.Name", timeout).unwrap()
+    );
+    assert!(daemon.name_owner("com.test.Name").unwrap().is_some());
+}
+
+fn activate(daemon_type: DaemonType) {
+    let daemon = Launcher::new(daemon_type)
+        .service_fn("com.test.A")
+        .service_fn("com.test.B")
+        .launch()
+        .unwrap();
+
+    for name in &["com.test.B", "com.test.A"] {
+        // The service exits without acquiring the name, so the call fails.
+        Command::new("dbus-send")
+            .arg(format!("--bus={}", daemon.address()))
+            .args([
+                "--print-reply",
+                "--dest=org.freedesktop.DBus",
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus.StartServiceByName",
+                &format!("string:{}", name),
+                "uint32:0",
+            ])
+            .output()
+            .unwrap();
+    }
+
+    let start = Instant::now();
+    let activations = loop {
+        let activations = daemon.activations().unwrap();
+        if activations.len() == 2 && activations.iter().all(|a| a.exit_code().is_some())
+        {
+            break activations;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!("com.test.B", activations[0].name());
+    assert_eq!(Some(12), activations[0].exit_code());
+    assert_eq!("com.test.A", activations[1].name());
+    assert_eq!(Some(11), activations[1].exit_code());
+}
+
+/// Names missing from service_main fail the launch.
+fn unregistered() {
+    let err = Launcher::daemon()
+        .service_fn("com.test.Unregistered")
+        .launch()
+        .unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, err.kind());
+}
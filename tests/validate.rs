@@ -0,0 +1,113 @@
+use dbus_launch::{Auth, BusName, DaemonType, Launcher, Resource};
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+
+/// Service names are validated according to D-Bus specification.
+#[test]
+fn invalid_service_name() {
+    for name in &["", "com", "com..example", "com.example/Test", ":1.0"] {
+        let error = Launcher::daemon()
+            .service(name, "/usr/bin/false")
+            .validate()
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind(), "{}", name);
+    }
+}
+
+/// Service name can be provided as a validated bus name.
+#[test]
+fn bus_name_service() {
+    let name = BusName::new("com.example.Test").unwrap();
+    Launcher::daemon()
+        .service(&name, "/usr/bin/false")
+        .validate()
+        .unwrap();
+}
+
+/// Non-UTF-8 paths are rejected instead of causing a panic.
+#[test]
+fn non_utf8_service_dir() {
+    let dir = OsStr::from_bytes(b"/tmp/\xff");
+    let error = Launcher::daemon().service_dir(dir).launch().unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, error.kind());
+}
+
+#[test]
+fn malformed_listen_address() {
+    for daemon_type in &[DaemonType::DBusDaemon, DaemonType::DBusBroker] {
+        let error = Launcher::new(*daemon_type)
+            .listen("unix")
+            .validate()
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+    }
+}
+
+/// Settings not supported by dbus-broker are reported as errors.
+#[test]
+fn broker_unsupported() {
+    let error = Launcher::broker().allow_anonymous().validate().unwrap_err();
+    assert_eq!(ErrorKind::Unsupported, error.kind());
+
+    let error = Launcher::broker()
+        .auth(Auth::Anonymous)
+        .validate()
+        .unwrap_err();
+    assert_eq!(ErrorKind::Unsupported, error.kind());
+
+    Launcher::broker().auth(Auth::External).validate().unwrap();
+}
+
+/// Soft limit cannot exceed the hard limit.
+#[test]
+fn rlimit_soft_above_hard() {
+    let error = Launcher::daemon()
+        .rlimit(Resource::NoFile, 2, 1)
+        .validate()
+        .unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, error.kind());
+}
+
+/// File descriptors used by the launcher cannot be overridden.
+#[test]
+fn inherit_reserved_fd() {
+    let (_a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+    let error = Launcher::broker()
+        .listen("unix:path=/tmp/a")
+        .listen("unix:path=/tmp/b")
+        .inherit_fd(4, b)
+        .validate()
+        .unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, error.kind());
+}
+
+#[test]
+fn valid() {
+    Launcher::daemon()
+        .listen("tcp:host=localhost")
+        .allow_anonymous()
+        .auth(Auth::Anonymous)
+        .service("com.example.Test", "/usr/bin/false")
+        .validate()
+        .unwrap();
+}
+
+/// Sockets created inside the sandbox are inaccessible from outside.
+#[cfg(target_os = "linux")]
+#[test]
+fn sandbox_listen_path() {
+    for listen in &["unix:path=/tmp/dbus-launch-test", "unix:dir=/tmp"] {
+        let error = Launcher::daemon()
+            .sandbox(dbus_launch::Sandbox::new().network(false).clone())
+            .listen(listen)
+            .validate()
+            .unwrap_err();
+        assert_eq!(ErrorKind::Unsupported, error.kind(), "{}", listen);
+    }
+    Launcher::daemon()
+        .sandbox(dbus_launch::Sandbox::new().network(false).clone())
+        .listen("tcp:host=localhost")
+        .validate()
+        .unwrap();
+}